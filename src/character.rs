@@ -0,0 +1,211 @@
+use chrono::DateTime;
+use chrono::prelude::Utc;
+use error::RollError;
+use receipt;
+use std::collections::BTreeMap;
+use uuid::Uuid;
+
+/// A character sheet whose attributes can be used in commands (e.g. "1d20+@str_mod+@prof")
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Character {
+    /// Unique identifier of the character
+    pub id: String,
+
+    pub name: String,
+
+    /// Value of each attribute, by name
+    pub attributes: BTreeMap<String, i32>,
+
+    /// Secret needed to change or delete the character, only given out when it is created
+    #[serde(skip)]
+    pub owner_token: String,
+
+    /// Timestamp of when the character was last saved
+    pub timestamp: DateTime<Utc>,
+}
+
+impl Character {
+    pub fn new(name: &str, attributes: BTreeMap<String, i32>) -> Result<Character, RollError> {
+        for name in attributes.keys() {
+            if name.is_empty() || !name.chars().all(is_attribute_char) {
+                return Err(RollError::Parse {
+                    offset: 0,
+                    message: format!("\"{}\" is not a valid attribute, use letters, digits and _", name),
+                });
+            }
+        }
+        Ok(Character {
+            id: Uuid::new_v4().to_string(),
+            name: name.to_string(),
+            attributes,
+            owner_token: receipt::generate_key().map_err(|e| RollError::Random { offset: 0, message: e.to_string() })?,
+            timestamp: Utc::now(),
+        })
+    }
+
+    /// If a token is the one given out when the character was created, compared in constant time
+    pub fn is_owner(&self, token: &str) -> bool {
+        receipt::constant_time_eq(self.owner_token.as_bytes(), token.as_bytes())
+    }
+}
+
+/// An attribute that was substituted into a command
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Substitution {
+    pub attribute: String,
+
+    /// Byte offset of the reference in the command
+    pub offset: usize,
+
+    pub value: i32,
+}
+
+fn is_attribute_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+/// How a value is written into the command, negative values are wrapped in parentheses so they keep
+/// their sign (e.g. "1d20+(-1)")
+fn format_value(value: i32) -> String {
+    if value < 0 {
+        format!("({})", value)
+    } else {
+        value.to_string()
+    }
+}
+
+/// Replace every `@attribute` in a command with its value
+///
+/// The name of an attribute runs until the first character that isn't a letter, digit or _.
+/// Comments are left as they are, so they can mention people (e.g. "1d20 [ping @gm]").
+pub fn resolve(command: &str, attributes: &BTreeMap<String, i32>) -> Result<(String, Vec<Substitution>), RollError> {
+    let mut equation = String::new();
+    let mut substitutions = vec![];
+    let mut is_comment = false;
+    let mut chars = command.char_indices().peekable();
+    while let Some((offset, c)) = chars.next() {
+        match c {
+            '[' => is_comment = true,
+            ']' => is_comment = false,
+            _ => {} // do nothing
+        }
+        if c != '@' || is_comment {
+            equation.push(c);
+            continue;
+        }
+
+        let mut name = String::new();
+        while let Some(&(_, c)) = chars.peek() {
+            if !is_attribute_char(c) {
+                break;
+            }
+            name.push(c);
+            chars.next();
+        }
+        if name.is_empty() {
+            return Err(RollError::Parse {
+                offset,
+                message: "Expected an attribute after \"@\"".to_string(),
+            });
+        }
+
+        let value = *attributes.get(&name).ok_or_else(|| RollError::Parse {
+            offset,
+            message: format!("Unknown attribute \"@{}\"", name),
+        })?;
+        equation.push_str(&format_value(value));
+        substitutions.push(Substitution { attribute: name, offset, value });
+    }
+    Ok((equation, substitutions))
+}
+
+/// Map an offset in a resolved command back to the command that was sent
+///
+/// Offsets inside a substituted value point at the `@` of its attribute.
+pub fn original_offset(offset: usize, substitutions: &[Substitution]) -> usize {
+    // How much longer the resolved command is than the original, up to the current substitution
+    let mut growth: isize = 0;
+    for substitution in substitutions.iter() {
+        let start = (substitution.offset as isize + growth) as usize;
+        let length = format_value(substitution.value).len();
+        if offset < start {
+            break;
+        }
+        if offset < start + length {
+            return substitution.offset;
+        }
+        growth += length as isize - (substitution.attribute.len() + 1) as isize;
+    }
+    (offset as isize - growth) as usize
+}
+
+#[test]
+fn it_can_resolve_attributes() {
+    let mut attributes = BTreeMap::new();
+    attributes.insert("str_mod".to_string(), 3);
+    attributes.insert("prof".to_string(), 2);
+    attributes.insert("dex_mod".to_string(), -1);
+    let character = Character::new("Valeros", attributes).unwrap();
+    assert!(character.is_owner(&character.owner_token));
+    assert!(!character.is_owner(""));
+    assert!(!::serde_json::to_string(&character).unwrap().contains(&character.owner_token));
+
+    let (equation, substitutions) = resolve("1d20+@str_mod+@prof", &character.attributes).unwrap();
+    assert_eq!(equation, "1d20+3+2");
+    assert_eq!(substitutions, vec![
+        Substitution { attribute: "str_mod".to_string(), offset: 5, value: 3 },
+        Substitution { attribute: "prof".to_string(), offset: 14, value: 2 },
+    ]);
+
+    let (equation, _) = resolve("1d20+@dex_mod [Initiative]", &character.attributes).unwrap();
+    assert_eq!(equation, "1d20+(-1) [Initiative]");
+    assert!(::roll(&equation).is_ok());
+
+    // Commands without references are left alone
+    let (equation, substitutions) = resolve("4d6kh3", &BTreeMap::new()).unwrap();
+    assert_eq!(equation, "4d6kh3");
+    assert!(substitutions.is_empty());
+}
+
+#[test]
+fn it_leaves_comments_alone() {
+    let mut attributes = BTreeMap::new();
+    attributes.insert("gm".to_string(), 3);
+    let (equation, substitutions) = resolve("1d20+@gm [ping @gm]", &attributes).unwrap();
+    assert_eq!(equation, "1d20+3 [ping @gm]");
+    assert_eq!(substitutions.len(), 1);
+    assert!(resolve("1d20 [ping @someone]", &BTreeMap::new()).is_ok());
+}
+
+#[test]
+fn it_can_map_offsets_back_to_the_command() {
+    let mut attributes = BTreeMap::new();
+    attributes.insert("str_mod".to_string(), 3);
+    attributes.insert("dex_mod".to_string(), -1);
+
+    // "1d20+@dex_mod+@str_mod+" resolves to "1d20+(-1)+3+"
+    let command = "1d20+@dex_mod+@str_mod+";
+    let (equation, substitutions) = resolve(command, &attributes).unwrap();
+    assert_eq!(equation, "1d20+(-1)+3+");
+    assert_eq!(original_offset(4, &substitutions), 4);
+    assert_eq!(original_offset(6, &substitutions), 5);
+    assert_eq!(original_offset(9, &substitutions), 13);
+    assert_eq!(original_offset(10, &substitutions), 14);
+    assert_eq!(original_offset(12, &substitutions), command.len());
+
+    let error = ::roll(&equation).unwrap_err();
+    assert_eq!(original_offset(error.offset(), &substitutions), command.len());
+}
+
+#[test]
+fn it_can_report_attribute_errors() {
+    let mut attributes = BTreeMap::new();
+    attributes.insert("str_mod".to_string(), 3);
+    let error = resolve("1d20+@wis_mod", &attributes).unwrap_err();
+    assert_eq!(error.offset(), 5);
+    assert_eq!(error.message(), "Unknown attribute \"@wis_mod\"");
+    assert_eq!(resolve("1d20+@", &attributes).unwrap_err().offset(), 5);
+
+    attributes.insert("str mod".to_string(), 3);
+    assert!(Character::new("Valeros", attributes).is_err());
+}
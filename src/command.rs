@@ -0,0 +1,1147 @@
+use die::*;
+use error::RollError;
+use expression::{Expression, Function, Operator, Rounding};
+use rng::DiceRng;
+#[cfg(test)]
+use rng::SeededRng;
+use roll::*;
+use ttml::arg::{Arg, ArgValue, ComparisonArg, RollArg};
+use ttml::parser::parse_step_p;
+
+/// Deepest nesting of parentheses, functions and signs in a command
+pub const MAX_DEPTH: usize = 64;
+
+/// A parsed roll command: the dice groups to roll and the equation that combines them
+#[derive(Clone)]
+pub struct Command {
+    /// Comments that were not attached to a dice group
+    pub comment: String,
+
+    /// Equation combining the dice groups and constants
+    pub expression: Expression,
+
+    /// Flags for each group of dice, referenced by index from the expression
+    pub groups: Vec<RollFlags>,
+}
+
+impl Command {
+    /// Roll every group of dice and evaluate the equation
+    pub fn roll<R: DiceRng + ?Sized>(self, rounding: Rounding, rng: &mut R) -> Result<Roll, RollError> {
+        let mut rolls: Vec<Roll> = vec![];
+        for flags in self.groups.into_iter() {
+            rolls.push(Roll::new(flags, rng)?);
+        }
+        Roll::from_expression(self.expression, rolls, self.comment, rounding).ok_or_else(|| RollError::Evaluate {
+            offset: 0,
+            message: "Could not evaluate the equation, check for division by zero".to_string(),
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Close,
+    Comma,
+    Comment(String),
+    Dice(String),
+    Function(Function),
+    Minus,
+    Number(i32),
+    Open,
+    Plus,
+    Slash,
+    Star,
+}
+
+/// Characters that end a dice group
+fn is_delimiter(c: char) -> bool {
+    match c {
+        '+' | '-' | '*' | '/' | '(' | ')' | ',' | '[' | ' ' | '\t' => true,
+        _ => false,
+    }
+}
+
+/// Read a bracketed section (e.g. a comment or custom sides), returning its contents and the
+/// position after the closing bracket
+fn read_brackets(chars: &[(usize, char)], start: usize) -> Option<(String, usize)> {
+    let mut text = String::new();
+    let mut depth = 0;
+    for i in start..chars.len() {
+        let (_, c) = chars[i];
+        match c {
+            '[' => {
+                if depth > 0 {
+                    text.push(c);
+                }
+                depth += 1;
+            },
+            ']' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some((text, i + 1));
+                }
+                text.push(c);
+            },
+            _ => text.push(c),
+        }
+    }
+    None
+}
+
+/// How a token is written in a command, used in error messages
+fn describe(token: &Token) -> String {
+    match token {
+        &Token::Close => ")".to_string(),
+        &Token::Comma => ",".to_string(),
+        &Token::Comment(ref comment) => "[".to_string() + comment + "]",
+        &Token::Dice(ref text) => text.clone(),
+        &Token::Function(function) => function.name().to_string(),
+        &Token::Minus => "-".to_string(),
+        &Token::Number(n) => n.to_string(),
+        &Token::Open => "(".to_string(),
+        &Token::Plus => "+".to_string(),
+        &Token::Slash => "/".to_string(),
+        &Token::Star => "*".to_string(),
+    }
+}
+
+/// Error for an unexpected token, or for the end of the command when there is no token
+fn unexpected(token: Option<&Token>, offset: usize) -> RollError {
+    let message = match token {
+        Some(token) => format!("Unexpected \"{}\"", describe(token)),
+        None => "Unexpected end of command".to_string(),
+    };
+    RollError::Parse { offset, message }
+}
+
+/// Error for a bracket that is never closed
+fn unclosed(offset: usize) -> RollError {
+    RollError::Parse { offset, message: "Missing \"]\" to close \"[\"".to_string() }
+}
+
+/// Split a command into numbers, operators, functions, comments and groups of dice, along with the
+/// byte offset each one starts at
+fn tokenize(command: &str) -> Result<Vec<(Token, usize)>, RollError> {
+    let chars: Vec<(usize, char)> = command.char_indices().collect();
+    let mut tokens = vec![];
+    let mut i = 0;
+    while i < chars.len() {
+        let (offset, c) = chars[i];
+        match c {
+            ' ' | '\t' => { i += 1; },
+            '+' => { tokens.push((Token::Plus, offset)); i += 1; },
+            '-' => { tokens.push((Token::Minus, offset)); i += 1; },
+            '*' => { tokens.push((Token::Star, offset)); i += 1; },
+            '/' => { tokens.push((Token::Slash, offset)); i += 1; },
+            '(' => { tokens.push((Token::Open, offset)); i += 1; },
+            ')' => { tokens.push((Token::Close, offset)); i += 1; },
+            ',' => { tokens.push((Token::Comma, offset)); i += 1; },
+            '[' => {
+                let (comment, next) = read_brackets(&chars, i).ok_or_else(|| unclosed(offset))?;
+                tokens.push((Token::Comment(comment), offset));
+                i = next;
+            },
+            _ => {
+                let mut text = String::new();
+                while i < chars.len() {
+                    let (position, c) = chars[i];
+                    // Custom sides belong to the dice group (e.g. 1d[2,4,6])
+                    if c == '[' && text.ends_with('d') {
+                        let (sides, next) = read_brackets(&chars, i).ok_or_else(|| unclosed(position))?;
+                        text = text + "[" + &sides + "]";
+                        i = next;
+                        continue;
+                    }
+                    // Allow negative thresholds in comparisons (e.g. rr<-1)
+                    let is_sign = (c == '-' || c == '+') && (text.ends_with('<') || text.ends_with('>') || text.ends_with('='));
+                    if is_delimiter(c) && !is_sign {
+                        break;
+                    }
+                    text.push(c);
+                    i += 1;
+                }
+
+                let next = chars[i..].iter().map(|&(_, c)| c).find(|c| *c != ' ' && *c != '\t');
+                if text.chars().all(|c| c.is_digit(10)) {
+                    let number = text.parse().map_err(|_| RollError::LimitExceeded {
+                        offset,
+                        message: format!("{} is too large", text),
+                    })?;
+                    tokens.push((Token::Number(number), offset));
+                } else if text.chars().all(|c| c.is_alphabetic()) && next == Some('(') {
+                    let function = Function::from_name(&text).ok_or_else(|| RollError::UnsupportedArgument {
+                        offset,
+                        message: format!("Unknown function \"{}\"", text),
+                    })?;
+                    tokens.push((Token::Function(function), offset));
+                } else {
+                    tokens.push((Token::Dice(text), offset));
+                }
+            },
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    comment: Vec<String>,
+
+    /// How deeply the current expression is nested, limited so parsing can't run out of stack
+    depth: usize,
+
+    groups: Vec<RollFlags>,
+
+    /// Length of the command, where errors at the end of the command are reported
+    length: usize,
+
+    position: usize,
+    tokens: Vec<(Token, usize)>,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position).map(|&(ref token, _)| token)
+    }
+
+    /// Byte offset of the next token
+    fn offset(&self) -> usize {
+        self.tokens.get(self.position).map(|&(_, offset)| offset).unwrap_or(self.length)
+    }
+
+    fn next(&mut self) -> Result<Token, RollError> {
+        match self.tokens.get(self.position).cloned() {
+            Some((token, _)) => {
+                self.position += 1;
+                Ok(token)
+            },
+            None => Err(unexpected(None, self.length)),
+        }
+    }
+
+    fn expect(&mut self, token: Token) -> Result<(), RollError> {
+        if self.peek() == Some(&token) {
+            self.position += 1;
+            Ok(())
+        } else {
+            Err(RollError::Parse {
+                offset: self.offset(),
+                message: format!("Expected \"{}\"", describe(&token)),
+            })
+        }
+    }
+
+    /// expression := term (("+" | "-") term)*
+    fn expression(&mut self) -> Result<Expression, RollError> {
+        let mut left = self.term()?;
+        loop {
+            let operator = match self.peek() {
+                Some(&Token::Plus) => Operator::Add,
+                Some(&Token::Minus) => Operator::Subtract,
+                _ => return Ok(left),
+            };
+            self.position += 1;
+            let right = self.term()?;
+            left = Expression::Operation { operator, left: Box::new(left), right: Box::new(right), value: 0 };
+        }
+    }
+
+    /// term := unary (("*" | "/") unary)*
+    fn term(&mut self) -> Result<Expression, RollError> {
+        let mut left = self.unary()?;
+        loop {
+            let operator = match self.peek() {
+                Some(&Token::Star) => Operator::Multiply,
+                Some(&Token::Slash) => Operator::Divide,
+                _ => return Ok(left),
+            };
+            self.position += 1;
+            let right = self.unary()?;
+            left = Expression::Operation { operator, left: Box::new(left), right: Box::new(right), value: 0 };
+        }
+    }
+
+    /// unary := ("-" | "+") unary | primary
+    ///
+    /// Every sign, parenthesis and function passes through here, so this is where nesting is counted.
+    fn unary(&mut self) -> Result<Expression, RollError> {
+        if self.depth >= MAX_DEPTH {
+            return Err(RollError::LimitExceeded {
+                offset: self.offset(),
+                message: format!("Can't nest parentheses, functions and signs more than {} deep", MAX_DEPTH),
+            });
+        }
+        self.depth += 1;
+        let expression = match self.peek() {
+            Some(&Token::Minus) => {
+                self.position += 1;
+                self.unary().map(|operand| Expression::Negate { operand: Box::new(operand), value: 0 })
+            },
+            Some(&Token::Plus) => {
+                self.position += 1;
+                self.unary()
+            },
+            _ => self.primary(),
+        };
+        self.depth -= 1;
+        expression
+    }
+
+    /// primary := (number | dice | function "(" expression ("," expression)* ")" | "(" expression ")") comment*
+    fn primary(&mut self) -> Result<Expression, RollError> {
+        let offset = self.offset();
+        let mut expression = match self.next()? {
+            Token::Number(value) => Expression::Number { value },
+            Token::Dice(text) => {
+                let flags = parse_flags(&text).map_err(|e| e.shift(offset))?;
+                let index = self.groups.len();
+                let equation = flags.equation.clone();
+                self.groups.push(flags);
+                Expression::Roll { equation, index, value: 0 }
+            },
+            Token::Function(function) => {
+                self.expect(Token::Open)?;
+                let mut arguments = vec![self.expression()?];
+                while self.peek() == Some(&Token::Comma) {
+                    self.position += 1;
+                    arguments.push(self.expression()?);
+                }
+                self.expect(Token::Close)?;
+                let arity_ok = match function {
+                    Function::Max | Function::Min => arguments.len() >= 1,
+                    _ => arguments.len() == 1,
+                };
+                if !arity_ok {
+                    return Err(RollError::Parse {
+                        offset,
+                        message: format!("{}() takes exactly one argument", function.name()),
+                    });
+                }
+                Expression::Function { function, arguments, value: 0 }
+            },
+            Token::Open => {
+                let inner = self.expression()?;
+                self.expect(Token::Close)?;
+                Expression::Group { expression: Box::new(inner), value: 0 }
+            },
+            token => return Err(unexpected(Some(&token), offset)),
+        };
+
+        // Attach comments to the dice group they follow, everything else belongs to the roll
+        while let Some(&Token::Comment(_)) = self.peek() {
+            let comment = match self.next() {
+                Ok(Token::Comment(comment)) => comment,
+                _ => unreachable!(),
+            };
+            match expression {
+                Expression::Roll { ref mut equation, index, .. } => {
+                    let flags = &mut self.groups[index];
+                    flags.comment = comment.clone();
+                    flags.equation = flags.equation.clone() + "[" + &comment + "]";
+                    *equation = flags.equation.clone();
+                },
+                _ => self.comment.push(comment),
+            }
+        }
+        Ok(expression)
+    }
+}
+
+/// Parse a roll command (e.g. "(2d6+3)*2" or "max(1d20,1d20)+5") into dice groups and an equation
+pub fn parse(command: &str) -> Result<Command, RollError> {
+    let mut parser = Parser {
+        comment: vec![],
+        depth: 0,
+        groups: vec![],
+        length: command.len(),
+        position: 0,
+        tokens: tokenize(command)?,
+    };
+    let expression = parser.expression()?;
+    if parser.position != parser.tokens.len() {
+        return Err(unexpected(parser.peek(), parser.offset()));
+    }
+    Ok(Command {
+        comment: parser.comment.join(" "),
+        expression,
+        groups: parser.groups,
+    })
+}
+
+/// Faces of a Fate die, as TTML custom sides
+const FATE_SIDES: &str = "[-1,0,1]";
+
+/// Modifiers understood by this API that TTML does not know about
+enum Extension {
+    /// Explode on a comparison, or on the maximum face when there is none (e.g. 1d6!, 1d6!!>5)
+    Explode(Explosion, Option<(ComparisonArg, i16)>),
+
+    /// Roll a single die several times and take the highest or lowest (e.g. 1d20adv, 1d20adv3)
+    Advantage(AdvantageMode, i16),
+
+    /// Dice that meet a comparison are critical hits (e.g. 1d20cs>=19)
+    Critical(ComparisonArg, i16),
+
+    /// Dice that meet a comparison are fumbles (e.g. 1d20cf<=2)
+    Fumble(ComparisonArg, i16),
+
+    /// Fate dice, rolled as custom sides of -1, 0 and 1 (e.g. 4dF)
+    Fate,
+
+    /// Drop the highest dice (e.g. 4d6dh1)
+    DropHigh(i16),
+
+    /// Drop the lowest dice (e.g. 4d6dl1)
+    DropLow(i16),
+
+    /// Count dice that meet a comparison as successes (e.g. 10d10s>=8)
+    Success(ComparisonArg, i16),
+
+    /// Count dice that meet a comparison as failures, cancelling successes (e.g. 10d10f<=1)
+    Failure(ComparisonArg, i16),
+
+    /// Count dice that meet a comparison as two successes (e.g. 10d10ds>=10)
+    DoubleSuccess(ComparisonArg, i16),
+}
+
+impl Extension {
+    /// Apply the modifier once TTML has filled in the rest of the flags
+    fn apply(self, flags: &mut RollFlags) {
+        match self {
+            Extension::Explode(explosion, comparison) => {
+                let (op, threshold, suffix) = match comparison {
+                    Some((op, threshold)) => {
+                        let suffix = comparison_symbol(&op).to_string() + &threshold.to_string();
+                        (op, threshold, suffix)
+                    },
+                    None => (ComparisonArg::GreaterThanOrEqual, flags.max, "".to_string()),
+                };
+                let symbol = match explosion {
+                    Explosion::Standard => "!",
+                    Explosion::Compound => "!!",
+                    Explosion::Penetrate => "!p",
+                };
+                flags.equation = flags.equation.clone() + symbol + &suffix;
+                flags.explode = threshold;
+                flags.explosion = explosion;
+                flags.explode_op = Some(op);
+            },
+            Extension::Advantage(mode, dice) => {
+                let symbol = match mode {
+                    AdvantageMode::Advantage => "adv",
+                    AdvantageMode::Disadvantage => "dis",
+                };
+                let count = if dice == 2 { "".to_string() } else { dice.to_string() };
+                flags.equation = flags.equation.clone() + symbol + &count;
+                flags.n = dice;
+                match mode {
+                    AdvantageMode::Advantage => flags.kh = 1,
+                    AdvantageMode::Disadvantage => flags.kl = 1,
+                };
+                flags.advantage = Some(mode);
+            },
+            Extension::Critical(op, threshold) => {
+                flags.equation = flags.equation.clone() + "cs" + comparison_symbol(&op) + &threshold.to_string();
+                flags.critical = threshold;
+                flags.critical_op = Some(op);
+            },
+            Extension::Fumble(op, threshold) => {
+                flags.equation = flags.equation.clone() + "cf" + comparison_symbol(&op) + &threshold.to_string();
+                flags.fumble = threshold;
+                flags.fumble_op = Some(op);
+            },
+            Extension::Fate => {
+                flags.equation = flags.equation.replacen(FATE_SIDES, "dF", 1);
+                flags.die = DieType::Fate;
+                flags.sides = None;
+                flags.min = -1;
+                flags.max = 1;
+            },
+            Extension::DropHigh(n) => {
+                flags.equation = flags.equation.clone() + "dh" + &n.to_string();
+                flags.dh = n;
+            },
+            Extension::DropLow(n) => {
+                flags.equation = flags.equation.clone() + "dl" + &n.to_string();
+                flags.dl = n;
+            },
+            Extension::Success(op, threshold) => {
+                flags.equation = flags.equation.clone() + "s" + comparison_symbol(&op) + &threshold.to_string();
+                flags.success = threshold;
+                flags.success_op = Some(op);
+            },
+            Extension::Failure(op, threshold) => {
+                flags.equation = flags.equation.clone() + "f" + comparison_symbol(&op) + &threshold.to_string();
+                flags.failure = threshold;
+                flags.failure_op = Some(op);
+            },
+            Extension::DoubleSuccess(op, threshold) => {
+                flags.equation = flags.equation.clone() + "ds" + comparison_symbol(&op) + &threshold.to_string();
+                flags.double = threshold;
+                flags.double_op = Some(op);
+            },
+        }
+    }
+}
+
+/// Turn a group into a dice pool when it has failure or double success thresholds but no success
+/// threshold, using its gt/gte/lt/lte comparison or the highest face as the success threshold
+fn complete_pool(flags: &mut RollFlags) {
+    if flags.success_op.is_some() || (flags.failure_op.is_none() && flags.double_op.is_none()) {
+        return;
+    }
+
+    let (op, threshold) = if flags.gt != 0 {
+        (ComparisonArg::GreaterThan, flags.gt as i16)
+    } else if flags.gte != 0 {
+        (ComparisonArg::GreaterThanOrEqual, flags.gte as i16)
+    } else if flags.lt != 0 {
+        (ComparisonArg::LessThan, flags.lt as i16)
+    } else if flags.lte != 0 {
+        (ComparisonArg::LessThanOrEqual, flags.lte as i16)
+    } else {
+        (ComparisonArg::GreaterThanOrEqual, flags.max)
+    };
+
+    // Dice that miss the threshold count as failures instead of being dropped
+    flags.gt = 0;
+    flags.gte = 0;
+    flags.lt = 0;
+    flags.lte = 0;
+    flags.success = threshold;
+    flags.success_op = Some(op);
+}
+
+/// Symbol used to write a comparison in an equation
+fn comparison_symbol(op: &ComparisonArg) -> &'static str {
+    match op {
+        &ComparisonArg::GreaterThan => ">",
+        &ComparisonArg::GreaterThanOrEqual => ">=",
+        &ComparisonArg::LessThan => "<",
+        &ComparisonArg::LessThanOrEqual => "<=",
+        &ComparisonArg::EqualTo => "=",
+    }
+}
+
+/// Read a comparison such as ">5", "<=2" or "=6" from the start of some text, returning the
+/// comparison and how many characters it used
+fn read_comparison(text: &str) -> Option<(ComparisonArg, i16, usize)> {
+    let (op, length) = if text.starts_with(">=") {
+        (ComparisonArg::GreaterThanOrEqual, 2)
+    } else if text.starts_with("<=") {
+        (ComparisonArg::LessThanOrEqual, 2)
+    } else if text.starts_with("==") {
+        (ComparisonArg::EqualTo, 2)
+    } else if text.starts_with(">") {
+        (ComparisonArg::GreaterThan, 1)
+    } else if text.starts_with("<") {
+        (ComparisonArg::LessThan, 1)
+    } else if text.starts_with("=") {
+        (ComparisonArg::EqualTo, 1)
+    } else {
+        return None;
+    };
+    let (threshold, digits) = read_number(&text[length..])?;
+    Some((op, threshold, length + digits))
+}
+
+/// Read a (possibly negative) number from the start of some text, returning the number and how
+/// many characters it used
+fn read_number(text: &str) -> Option<(i16, usize)> {
+    let length = text.char_indices()
+        .take_while(|&(i, c)| c.is_digit(10) || (i == 0 && c == '-'))
+        .count();
+    let number = text[..length].parse().ok()?;
+    Some((number, length))
+}
+
+/// Read a dice pool threshold (e.g. "s>=8", "f<=1" or "ds>=10") from the start of some text
+fn read_pool_extension(text: &str) -> Option<(Extension, usize)> {
+    let (prefix, length) = if text.starts_with("ds") {
+        ("ds", 2)
+    } else if text.starts_with("s") {
+        ("s", 1)
+    } else if text.starts_with("f") {
+        ("f", 1)
+    } else {
+        return None;
+    };
+    let (op, threshold, comparison) = read_comparison(&text[length..])?;
+    let extension = match prefix {
+        "ds" => Extension::DoubleSuccess(op, threshold),
+        "s" => Extension::Success(op, threshold),
+        _ => Extension::Failure(op, threshold),
+    };
+    Some((extension, length + comparison))
+}
+
+/// Pull the modifiers TTML does not understand out of a group of dice, returning them along with
+/// the text that is left for TTML to parse and the offset in the group of each byte of that text
+fn extract_extensions(group: &str) -> Result<(Vec<Extension>, String, Vec<usize>), RollError> {
+    let mut extensions = vec![];
+    let mut rest = String::new();
+    let mut offsets = vec![];
+    let mut i = 0;
+    while i < group.len() {
+        let text = &group[i..];
+        if text.starts_with("[") {
+            // Leave custom sides untouched
+            let length = text.find(']').ok_or_else(|| unclosed(i))? + 1;
+            rest.push_str(&text[..length]);
+            offsets.extend(i..(i + length));
+            i += length;
+        } else if text.starts_with("!") {
+            let (explosion, symbol) = if text.starts_with("!!") {
+                (Explosion::Compound, 2)
+            } else if text.starts_with("!p") {
+                (Explosion::Penetrate, 2)
+            } else {
+                (Explosion::Standard, 1)
+            };
+            match read_comparison(&text[symbol..]) {
+                Some((op, threshold, length)) => {
+                    extensions.push(Extension::Explode(explosion, Some((op, threshold))));
+                    i += symbol + length;
+                },
+                None => {
+                    extensions.push(Extension::Explode(explosion, None));
+                    i += symbol;
+                },
+            }
+        } else if text.starts_with("dF") {
+            // TTML only knows numbered dice, so Fate dice are passed on as custom sides
+            let sides = "d".to_string() + FATE_SIDES;
+            rest.push_str(&sides);
+            offsets.extend(sides.chars().map(|_| i));
+            extensions.push(Extension::Fate);
+            i += 2;
+        } else if text.starts_with("adv") || text.starts_with("dis") {
+            let mode = if text.starts_with("adv") { AdvantageMode::Advantage } else { AdvantageMode::Disadvantage };
+            let (dice, length) = read_number(&text[3..]).unwrap_or((2, 0));
+            if dice < 2 {
+                return Err(RollError::InvalidRange {
+                    offset: i + 3,
+                    message: format!("\"{}\" needs at least 2 dice", &text[..3]),
+                });
+            }
+            extensions.push(Extension::Advantage(mode, dice));
+            i += 3 + length;
+        } else if text.starts_with("cs") || text.starts_with("cf") {
+            let (op, threshold, length) = read_comparison(&text[2..]).ok_or_else(|| RollError::Parse {
+                offset: i + 2,
+                message: format!("Expected a comparison after \"{}\" (e.g. {}>=19)", &text[..2], &text[..2]),
+            })?;
+            extensions.push(if text.starts_with("cs") { Extension::Critical(op, threshold) } else { Extension::Fumble(op, threshold) });
+            i += 2 + length;
+        } else if text.starts_with("dh") || text.starts_with("dl") {
+            let (n, length) = read_number(&text[2..]).ok_or_else(|| RollError::Parse {
+                offset: i + 2,
+                message: format!("Expected the number of dice to drop after \"{}\"", &text[..2]),
+            })?;
+            extensions.push(if text.starts_with("dh") { Extension::DropHigh(n) } else { Extension::DropLow(n) });
+            i += 2 + length;
+        } else if let Some((extension, length)) = read_pool_extension(text) {
+            extensions.push(extension);
+            i += length;
+        } else {
+            let c = text.chars().next().unwrap();
+            rest.push(c);
+            offsets.extend(i..(i + c.len_utf8()));
+            i += c.len_utf8();
+        }
+    }
+    Ok((extensions, rest, offsets))
+}
+
+/// Check that a group of dice can be rolled
+fn validate(flags: &RollFlags) -> Result<(), RollError> {
+    if flags.n < 0 || flags.n > MAX_DICE {
+        return Err(RollError::LimitExceeded {
+            offset: 0,
+            message: format!("Can't roll more than {} dice at once", MAX_DICE),
+        });
+    }
+
+    // Custom sides are picked by an index from the random number generator, which is an i16
+    if let Some(ref sides) = flags.sides {
+        if sides.len() > i16::max_value() as usize {
+            return Err(RollError::LimitExceeded {
+                offset: 0,
+                message: format!("Dice can't have more than {} sides", i16::max_value()),
+            });
+        }
+    }
+
+    let is_empty = match flags.sides {
+        Some(ref sides) => sides.is_empty(),
+        None => flags.min > flags.max,
+    };
+    if is_empty {
+        return Err(RollError::InvalidRange {
+            offset: 0,
+            message: format!("A die needs at least one side, the lowest face ({}) is higher than the highest ({})", flags.min, flags.max),
+        });
+    }
+
+    // Rerolling every face would never stop, the faces of a range are all rerolled when both ends are
+    if let Some(ref op) = flags.rr_op {
+        let is_forever = match flags.sides {
+            Some(ref sides) => sides.iter().all(|face| compare(op, *face, flags.rr)),
+            None => compare(op, flags.min, flags.rr) && compare(op, flags.max, flags.rr),
+        };
+        if is_forever {
+            return Err(RollError::InvalidRange {
+                offset: 0,
+                message: format!("Every face would be rerolled forever by rr{}{}", comparison_symbol(op), flags.rr),
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Parse a single group of dice (e.g. "4d6kh3") into flags using TTML
+///
+/// Errors are reported with their offset into the group.
+pub fn parse_flags(group: &str) -> Result<RollFlags, RollError> {
+    let length = group.len();
+    let (extensions, rest, offsets) = extract_extensions(group)?;
+
+    // Parse the roll command as if we're passing it through TTML
+    let prefix = "!roll ";
+    let input = prefix.to_string() + &rest;
+    let step = match parse_step_p(input.as_bytes()) {
+        Ok((remaining, step)) => {
+            // Anything TTML could not read is an argument it doesn't support
+            let unread = String::from_utf8_lossy(remaining).trim().to_string();
+            if !unread.is_empty() {
+                let read = (input.len() - remaining.len()).saturating_sub(prefix.len());
+                return Err(RollError::UnsupportedArgument {
+                    offset: offsets.get(read).cloned().unwrap_or(length),
+                    message: format!("Unsupported argument \"{}\"", unread),
+                });
+            }
+            step
+        },
+        Err(_) => return Err(RollError::Parse {
+            offset: 0,
+            message: format!("\"{}\" is not a valid group of dice", group),
+        }),
+    };
+
+    // Build a list of flags
+    let mut flags = RollFlags::new();
+
+    // Loop through each step, push the dice when necessary
+    for arg in &step.args {
+        if let &Arg::Roll(RollArg::N(ArgValue::Number(n))) = arg {
+            if n as i64 > MAX_DICE as i64 {
+                return Err(RollError::LimitExceeded {
+                    offset: 0,
+                    message: format!("Can't roll more than {} dice at once", MAX_DICE),
+                });
+            }
+            flags.n = n as i16;
+            flags.equation = flags.equation + &n.to_string();
+        } else if let &Arg::Roll(RollArg::D(ArgValue::Number(d))) = arg {
+            if d as i64 > i16::max_value() as i64 {
+                return Err(RollError::LimitExceeded {
+                    offset: 0,
+                    message: format!("Dice can't have more than {} sides", i16::max_value()),
+                });
+            }
+            flags.max = d as i16;
+            flags.die = match d {
+                100   => DieType::D100,
+                20    => DieType::D20,
+                12    => DieType::D12,
+                10    => DieType::D10,
+                8     => DieType::D8,
+                6     => DieType::D6,
+                4     => DieType::D4,
+                _     => DieType::Other,
+            };
+            flags.equation = flags.equation + &"d" + &d.to_string();
+        } else if let &Arg::Roll(RollArg::H(ArgValue::Number(h))) = arg {
+            flags.kh = h as i16;
+            flags.equation = flags.equation + &"kh" + &h.to_string();
+        } else if let &Arg::Roll(RollArg::L(ArgValue::Number(l))) = arg {
+            flags.kl = l as i16;
+            flags.equation = flags.equation + &"kl" + &l.to_string();
+        } else if let &Arg::Roll(RollArg::GT(ArgValue::Number(gt))) = arg {
+            flags.gt = gt as u16;
+            flags.equation = flags.equation + &"gt" + &gt.to_string();
+        } else if let &Arg::Roll(RollArg::GTE(ArgValue::Number(gte))) = arg {
+            flags.gte = gte as u16;
+            flags.equation = flags.equation + &"gte" + &gte.to_string();
+        } else if let &Arg::Roll(RollArg::LT(ArgValue::Number(lt))) = arg {
+            flags.lt = lt as u16;
+            flags.equation = flags.equation + &"lt" + &lt.to_string();
+        } else if let &Arg::Roll(RollArg::LTE(ArgValue::Number(lte))) = arg {
+            flags.lte = lte as u16;
+            flags.equation = flags.equation + &"lte" + &lte.to_string();
+        } else if let &Arg::Roll(RollArg::RR(ref comparitive)) = arg {
+            flags.rr = match &comparitive.value {
+                &ArgValue::Number(n) => n as i16,
+                _ => 0
+            };
+            match comparitive.op {
+                ComparisonArg::GreaterThan => {
+                    flags.rr_op = Some(ComparisonArg::GreaterThan);
+                    flags.equation = flags.equation + &"rr>" + &flags.rr.to_string();
+                },
+                ComparisonArg::GreaterThanOrEqual => {
+                    flags.rr_op = Some(ComparisonArg::GreaterThanOrEqual);
+                    flags.equation = flags.equation + &"rr>=" + &flags.rr.to_string();
+                },
+                ComparisonArg::LessThan => {
+                    flags.rr_op = Some(ComparisonArg::LessThan);
+                    flags.equation = flags.equation + &"rr<" + &flags.rr.to_string();
+                },
+                ComparisonArg::LessThanOrEqual => {
+                    flags.rr_op = Some(ComparisonArg::LessThanOrEqual);
+                    flags.equation = flags.equation + &"rr<=" + &flags.rr.to_string();
+                },
+                ComparisonArg::EqualTo => {
+                    flags.rr_op = Some(ComparisonArg::EqualTo);
+                    flags.equation = flags.equation + &"rr==" + &flags.rr.to_string();
+                },
+            };
+        } else if let &Arg::Roll(RollArg::RO(ref comparitive)) = arg {
+            flags.ro = match &comparitive.value {
+                &ArgValue::Number(n) => n as i16,
+                _ => 0
+            };
+            match comparitive.op {
+                ComparisonArg::GreaterThan => {
+                    flags.ro_op = Some(ComparisonArg::GreaterThan);
+                    flags.equation = flags.equation + &"ro>" + &flags.ro.to_string();
+                },
+                ComparisonArg::GreaterThanOrEqual => {
+                    flags.ro_op = Some(ComparisonArg::GreaterThanOrEqual);
+                    flags.equation = flags.equation + &"ro>=" + &flags.ro.to_string();
+                },
+                ComparisonArg::LessThan => {
+                    flags.ro_op = Some(ComparisonArg::LessThan);
+                    flags.equation = flags.equation + &"ro<" + &flags.ro.to_string();
+                },
+                ComparisonArg::LessThanOrEqual => {
+                    flags.ro_op = Some(ComparisonArg::LessThanOrEqual);
+                    flags.equation = flags.equation + &"ro<=" + &flags.ro.to_string();
+                },
+                ComparisonArg::EqualTo => {
+                    flags.ro_op = Some(ComparisonArg::EqualTo);
+                    flags.equation = flags.equation + &"ro==" + &flags.ro.to_string();
+                },
+            };
+        } else if let &Arg::Roll(RollArg::ModifierPos(ArgValue::Number(mp))) = arg {
+            if mp != 0 {
+                flags.modifiers.push(mp as i16);
+                flags.equation = flags.equation + &"+" + &mp.to_string();
+            }
+        } else if let &Arg::Roll(RollArg::ModifierNeg(ArgValue::Number(mn))) = arg {
+            if mn != 0 {
+                flags.modifiers.push(mn as i16 * -1);
+                flags.equation = flags.equation + &"-" + &mn.to_string();
+            }
+        } else if let &Arg::Roll(RollArg::Max(ArgValue::Number(max))) = arg {
+            flags.max = max as i16;
+            flags.equation = flags.equation + &"max" + &max.to_string();
+        } else if let &Arg::Roll(RollArg::Min(ArgValue::Number(min))) = arg {
+            flags.min = min as i16;
+            flags.equation = flags.equation + &"min" + &min.to_string();
+        } else if let &Arg::Roll(RollArg::Sides(ref r_sides)) = arg {
+            let mut min = 0;
+            let mut max = 0;
+            let sides: Vec<i16> = r_sides.into_iter().map(|side| (
+                match side {
+                    &ArgValue::Number(n) => {
+                        if n < min || n == 0 {
+                            min = n.clone()
+                        } else if n > max {
+                            max = n.clone()
+                        }
+                        n.clone() as i16
+                    },
+                    _ => 0 as i16
+                }
+            )).collect();
+            flags.sides = Some(sides.clone());
+            flags.min = min as i16;
+            flags.max = max as i16;
+            let side_strs: Vec<String> = sides.into_iter().map(|side| (side.to_string())).collect();
+            flags.equation = flags.equation + &"[" + &side_strs.join(",") + &"]";
+        } else if let &Arg::Roll(RollArg::Comment(ArgValue::Text(ref comment))) = arg {
+            flags.comment = comment.to_string();
+            flags.equation = flags.equation + &"[" + &flags.comment + &"]";
+        } else if let &Arg::Roll(RollArg::Primitive(_)) = arg {
+            // Groups are split by the expression parser before they reach TTML
+            return Err(RollError::Parse {
+                offset: 0,
+                message: format!("Unexpected operator in \"{}\"", group),
+            });
+        } else {
+            return Err(RollError::UnsupportedArgument {
+                offset: 0,
+                message: format!("\"{}\" uses an argument that isn't supported", group),
+            });
+        }
+    }
+
+    // Advantage rolls one die several times, so it can't be mixed with other ways of keeping dice
+    let advantages = extensions.iter().filter(|e| match e { &&Extension::Advantage(..) => true, _ => false }).count();
+    let drops = extensions.iter().any(|e| match e { &Extension::DropHigh(_) | &Extension::DropLow(_) => true, _ => false });
+    if advantages > 0 && (advantages > 1 || drops || flags.n != 1 || flags.kh != 0 || flags.kl != 0) {
+        return Err(RollError::InvalidRange {
+            offset: 0,
+            message: format!("Advantage and disadvantage roll a single die without keeping or dropping others (e.g. 1d20adv), not \"{}\"", group),
+        });
+    }
+
+    for extension in extensions.into_iter() {
+        extension.apply(&mut flags);
+    }
+    complete_pool(&mut flags);
+    validate(&flags)?;
+
+    Ok(flags)
+}
+
+#[test]
+fn it_can_parse_expressions() {
+    let command = parse("(2d6+3)*2").unwrap();
+    assert_eq!(command.groups.len(), 1);
+    assert_eq!(command.expression.to_string(), "(2d6 + 3) * 2");
+
+    let command = parse("max(1d20,1d20)+5").unwrap();
+    assert_eq!(command.groups.len(), 2);
+    assert_eq!(command.expression.to_string(), "max(1d20, 1d20) + 5");
+    assert_eq!(command.expression.modifiers(), vec![5]);
+
+    let command = parse("-1d4 + 2 * 3").unwrap();
+    assert_eq!(command.expression.to_string(), "-1d4 + 2 * 3");
+
+    assert!(parse("1d20+").is_err());
+    assert!(parse("(1d20").is_err());
+    assert!(parse("foo(1d20)").is_err());
+    assert!(parse("abs(1d20,2)").is_err());
+}
+
+#[test]
+fn it_can_report_errors() {
+    assert_eq!(parse("1d20+").err(), Some(RollError::Parse {
+        offset: 5,
+        message: "Unexpected end of command".to_string(),
+    }));
+    assert_eq!(parse("(1d20").err(), Some(RollError::Parse {
+        offset: 5,
+        message: "Expected \")\"".to_string(),
+    }));
+    assert_eq!(parse("1d20 * )").err(), Some(RollError::Parse {
+        offset: 7,
+        message: "Unexpected \")\"".to_string(),
+    }));
+    assert_eq!(parse("2 + foo(1d20)").err(), Some(RollError::UnsupportedArgument {
+        offset: 4,
+        message: "Unknown function \"foo\"".to_string(),
+    }));
+    assert_eq!(parse("1d20 [unclosed").err().map(|e| e.offset()), Some(5));
+
+    // Errors inside a group of dice are reported from the start of the command
+    assert_eq!(parse("1d20+4d6dl").err().map(|e| e.offset()), Some(10));
+    assert!(parse("1d20+1d20xyz").err().map(|e| e.offset()).unwrap() >= 5);
+
+    match parse("1001d6") {
+        Err(RollError::LimitExceeded { .. }) => {}, // do nothing
+        _ => panic!("Rolling too many dice should exceed the limit"),
+    }
+    match parse("99999999999") {
+        Err(RollError::LimitExceeded { .. }) => {}, // do nothing
+        _ => panic!("Numbers too large for an i32 should exceed the limit"),
+    }
+    match parse("1d6rr<=6") {
+        Err(RollError::InvalidRange { .. }) => {}, // do nothing
+        _ => panic!("Rerolling every face should be an invalid range"),
+    }
+    match parse("1d20min30") {
+        Err(RollError::InvalidRange { .. }) => {}, // do nothing
+        _ => panic!("A minimum above the maximum should be an invalid range"),
+    }
+    assert!(parse("1d32767").is_ok());
+    let sides: Vec<String> = (0..40000).map(|_| "1".to_string()).collect();
+    match parse(&format!("1d[{}]", sides.join(","))) {
+        Err(RollError::LimitExceeded { .. }) => {}, // do nothing
+        _ => panic!("Too many custom sides should exceed the limit"),
+    }
+    let nested = "(".repeat(MAX_DEPTH - 1) + "1d20" + &")".repeat(MAX_DEPTH - 1);
+    assert!(parse(&nested).is_ok());
+    let nested = "(".repeat(MAX_DEPTH) + "1d20" + &")".repeat(MAX_DEPTH);
+    match parse(&nested) {
+        Err(RollError::LimitExceeded { offset, .. }) => assert_eq!(offset, MAX_DEPTH),
+        _ => panic!("Nesting too deeply should exceed the limit"),
+    }
+    match parse(&"-".repeat(100000)) {
+        Err(RollError::LimitExceeded { .. }) => {}, // do nothing
+        _ => panic!("Too many signs should exceed the limit"),
+    }
+    assert!(parse("1d32767rr<32767").is_ok());
+    match parse("1d32767rr>0") {
+        Err(RollError::InvalidRange { .. }) => {}, // do nothing
+        _ => panic!("Rerolling every face of the largest die should be an invalid range"),
+    }
+}
+
+#[test]
+fn it_can_roll_expressions() {
+    let mut rng = SeededRng::new(1);
+    let roll = parse("(2d6+3)*2").unwrap().roll(Rounding::Floor, &mut rng).unwrap();
+    assert_eq!(roll.rolls.len(), 1);
+    assert_eq!(roll.dice.len(), 2);
+    assert_eq!(roll.value, (roll.raw_value + 3) * 2);
+
+    let roll = parse("1d20+2d6-1").unwrap().roll(Rounding::Floor, &mut rng).unwrap();
+    assert_eq!(roll.rolls.len(), 2);
+    assert_eq!(roll.modifiers, vec![-1]);
+    assert_eq!(roll.value, roll.rolls[0].value + roll.rolls[1].value - 1);
+
+    let roll = parse("ceil(7/2)+7/2").unwrap().roll(Rounding::Floor, &mut rng).unwrap();
+    assert_eq!(roll.value, 7);
+
+    assert!(parse("1d20/0").unwrap().roll(Rounding::Floor, &mut rng).is_err());
+}
+
+#[test]
+fn it_can_parse_exploding_dice() {
+    let flags = parse_flags("3d6!").unwrap();
+    assert_eq!(flags.n, 3);
+    assert_eq!(flags.explode, 6);
+    assert_eq!(flags.explode_op, Some(ComparisonArg::GreaterThanOrEqual));
+    assert_eq!(flags.equation, "3d6!");
+
+    let flags = parse_flags("1d10!>8").unwrap();
+    assert_eq!(flags.explode, 8);
+    assert_eq!(flags.explode_op, Some(ComparisonArg::GreaterThan));
+    assert_eq!(flags.equation, "1d10!>8");
+
+    let flags = parse_flags("2d[1,2,3]!=2").unwrap();
+    assert_eq!(flags.explode, 2);
+    assert_eq!(flags.explode_op, Some(ComparisonArg::EqualTo));
+
+    let flags = parse_flags("5d6!!").unwrap();
+    assert_eq!(flags.explosion, Explosion::Compound);
+    assert_eq!(flags.equation, "5d6!!");
+
+    let flags = parse_flags("2d6!p>=5").unwrap();
+    assert_eq!(flags.explosion, Explosion::Penetrate);
+    assert_eq!(flags.explode, 5);
+    assert_eq!(flags.equation, "2d6!p>=5");
+}
+
+#[test]
+fn it_can_parse_drop_dice() {
+    let flags = parse_flags("4d6dl1").unwrap();
+    assert_eq!(flags.n, 4);
+    assert_eq!(flags.dl, 1);
+    assert_eq!(flags.equation, "4d6dl1");
+
+    let flags = parse_flags("6d6kh4dl1").unwrap();
+    assert_eq!(flags.kh, 4);
+    assert_eq!(flags.dl, 1);
+    assert_eq!(flags.equation, "6d6kh4dl1");
+
+    let flags = parse_flags("3d20dh1").unwrap();
+    assert_eq!(flags.dh, 1);
+
+    assert!(parse_flags("4d6dl").is_err());
+}
+
+#[test]
+fn it_can_parse_dice_pools() {
+    let flags = parse_flags("10d10s>=8f<=1ds>=10").unwrap();
+    assert_eq!(flags.success, 8);
+    assert_eq!(flags.success_op, Some(ComparisonArg::GreaterThanOrEqual));
+    assert_eq!(flags.failure, 1);
+    assert_eq!(flags.failure_op, Some(ComparisonArg::LessThanOrEqual));
+    assert_eq!(flags.double, 10);
+    assert_eq!(flags.equation, "10d10s>=8f<=1ds>=10");
+
+    // A failure threshold turns a comparison into a pool rather than dropping dice
+    let flags = parse_flags("6d10gte7f<=1").unwrap();
+    assert_eq!(flags.gte, 0);
+    assert_eq!(flags.success, 7);
+    assert_eq!(flags.success_op, Some(ComparisonArg::GreaterThanOrEqual));
+
+    let flags = parse_flags("6d6f=1").unwrap();
+    assert_eq!(flags.success, 6);
+}
+
+#[test]
+fn it_can_parse_advantage() {
+    let flags = parse_flags("1d20adv").unwrap();
+    assert_eq!(flags.n, 2);
+    assert_eq!(flags.kh, 1);
+    assert_eq!(flags.advantage, Some(AdvantageMode::Advantage));
+    assert_eq!(flags.equation, "1d20adv");
+
+    let flags = parse_flags("1d20dis").unwrap();
+    assert_eq!(flags.n, 2);
+    assert_eq!(flags.kl, 1);
+    assert_eq!(flags.advantage, Some(AdvantageMode::Disadvantage));
+
+    // Elven accuracy rolls three dice
+    let flags = parse_flags("1d20adv3").unwrap();
+    assert_eq!(flags.n, 3);
+    assert_eq!(flags.equation, "1d20adv3");
+
+    assert!(parse_flags("2d20adv").is_err());
+    assert!(parse_flags("1d20adv1").is_err());
+    assert!(parse_flags("1d20advdis").is_err());
+    assert!(parse_flags("1d20advdl1").is_err());
+
+    for seed in 0..20 {
+        let roll = parse("1d20adv+5").unwrap().roll(Rounding::Floor, &mut SeededRng::new(seed)).unwrap();
+        let advantage = roll.rolls[0].advantage.as_ref().unwrap();
+        assert_eq!(advantage.mode, AdvantageMode::Advantage);
+        assert_eq!(advantage.discarded.len(), 1);
+        assert!(advantage.taken.value >= advantage.discarded[0].value);
+        assert_eq!(roll.value, advantage.taken.value as i32 + 5);
+    }
+}
+
+#[test]
+fn it_can_parse_criticals() {
+    let flags = parse_flags("1d20cs>=19cf<=2").unwrap();
+    assert_eq!(flags.critical, 19);
+    assert_eq!(flags.critical_op, Some(ComparisonArg::GreaterThanOrEqual));
+    assert_eq!(flags.fumble, 2);
+    assert_eq!(flags.fumble_op, Some(ComparisonArg::LessThanOrEqual));
+    assert_eq!(flags.equation, "1d20cs>=19cf<=2");
+    assert!(parse_flags("1d20cs").is_err());
+
+    // Only the die that is kept makes the roll critical
+    for seed in 0..50 {
+        let roll = parse("1d20advcs>=19").unwrap().roll(Rounding::Floor, &mut SeededRng::new(seed)).unwrap();
+        let taken = roll.advantage.as_ref().unwrap().taken.clone();
+        assert_eq!(roll.is_critical, taken.value >= 19);
+        assert_eq!(roll.is_fumble, taken.value == 1);
+        assert!(roll.dice.iter().all(|die| die.is_critical == (die.value >= 19)));
+    }
+}
+
+#[test]
+fn it_can_parse_fate_dice() {
+    let flags = parse_flags("4dF").unwrap();
+    assert_eq!(flags.n, 4);
+    assert_eq!(flags.die, DieType::Fate);
+    assert_eq!(flags.min, -1);
+    assert_eq!(flags.max, 1);
+    assert!(flags.sides.is_none());
+
+    for seed in 0..20 {
+        let roll = parse("4dF+1").unwrap().roll(Rounding::Floor, &mut SeededRng::new(seed)).unwrap();
+        assert!(roll.dice.iter().all(|d| d.die == DieType::Fate && d.value >= -1 && d.value <= 1 && d.symbol.is_some()));
+        assert!(roll.value >= -3 && roll.value <= 5);
+        assert_eq!(roll.ladder, fate_ladder(roll.value).map(|name| name.to_string()));
+    }
+
+    let roll = parse("1d20").unwrap().roll(Rounding::Floor, &mut SeededRng::new(1)).unwrap();
+    assert!(roll.ladder.is_none());
+}
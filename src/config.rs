@@ -1,9 +1,49 @@
 use rocket::Rocket;
 use rocket::fairing::{Fairing, Info, Kind};
+use roll_api::receipt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
 
 #[derive(Debug)]
 pub struct Config {
     pub access_control_allow_origin: String,
+
+    /// Most commands a single batch can roll
+    pub max_batch_size: usize,
+
+    /// Most trials a single simulation can roll
+    pub max_simulation_trials: u32,
+
+    /// Most milliseconds a single simulation can take
+    pub max_simulation_time: u64,
+
+    /// Secret key rolls are signed with, random for each run when not set and there is no roll history
+    pub signing_key: String,
+}
+
+/// Read the signing key kept next to the roll history, creating it the first time
+///
+/// Rolls in the history are signed with it, so they must still verify after a restart.
+fn history_key(path: &str) -> io::Result<String> {
+    let mut key = String::new();
+    match File::open(path) {
+        Ok(mut file) => {
+            file.read_to_string(&mut key)?;
+            return Ok(key.trim().to_string());
+        },
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}, // do nothing
+        Err(e) => return Err(e),
+    }
+
+    let key = receipt::generate_key()?;
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    options.open(path)?.write_all(key.as_bytes())?;
+    Ok(key)
 }
 
 pub struct ConfigMiddleware;
@@ -18,8 +58,20 @@ impl Fairing for ConfigMiddleware {
 
     fn on_attach(&self, rocket: Rocket) -> Result<Rocket, Rocket> {
         let access_control_allow_origin = rocket.config().get_str("access_control_allow_origin").unwrap_or("http://localhost:3000").to_string();
+        let max_batch_size = rocket.config().get_int("max_batch_size").unwrap_or(100) as usize;
+        let max_simulation_trials = rocket.config().get_int("max_simulation_trials").unwrap_or(100000) as u32;
+        let max_simulation_time = rocket.config().get_int("max_simulation_time").unwrap_or(2000) as u64;
+        let signing_key = match (rocket.config().get_str("signing_key"), rocket.config().get_str("roll_history")) {
+            (Ok(key), _) => key.to_string(),
+            (Err(_), Ok(history)) => history_key(&format!("{}.key", history)).expect("Could not read or create the signing key for the roll history"),
+            (Err(_), Err(_)) => receipt::generate_key().expect("Could not generate a signing key"),
+        };
         Ok(rocket.manage(Config {
             access_control_allow_origin,
+            max_batch_size,
+            max_simulation_trials,
+            max_simulation_time,
+            signing_key,
         }))
     }
 
@@ -20,8 +20,8 @@ impl Fairing for CORS {
         if request.method() == Method::Options || response.content_type() == Some(ContentType::JSON) {
             let config = request.guard::<State<Config>>().unwrap();
             response.set_header(Header::new("Access-Control-Allow-Origin", config.access_control_allow_origin.clone()));
-            response.set_header(Header::new("Access-Control-Allow-Methods", "POST, GET, OPTIONS"));
-            response.set_header(Header::new("Access-Control-Allow-Headers", "Content-Type"));
+            response.set_header(Header::new("Access-Control-Allow-Methods", "POST, GET, PUT, DELETE, OPTIONS"));
+            response.set_header(Header::new("Access-Control-Allow-Headers", "Content-Type, X-Member-Token, X-Owner-Token"));
             response.set_header(Header::new("Access-Control-Allow-Credentials", "true"));
         }
 
@@ -1,7 +1,11 @@
 use chrono::DateTime;
 use chrono::prelude::Utc;
-use rand::distributions::{IndependentSample, Range};
-use rand;
+use rng::DiceRng;
+#[cfg(test)]
+use rng::{FixedRng, SeededRng};
+use std::collections::BTreeMap;
+#[cfg(test)]
+use std::collections::HashMap;
 use uuid::Uuid;
 
 #[derive(Copy, Clone, Debug, Deserialize, PartialEq, Serialize)]
@@ -15,6 +19,7 @@ pub enum DieType {
     D100,
     Fate,
     Other,
+    Symbolic,
 }
 
 /// Determine the minimum number to roll based on the die type
@@ -29,6 +34,7 @@ fn get_die_min(die: &DieType) -> i16 {
         &DieType::D100 => 1,
         &DieType::Fate => -1,
         &DieType::Other => 0,
+        &DieType::Symbolic => 1,
     }
 }
 
@@ -44,10 +50,22 @@ fn get_die_max(die: &DieType) -> i16 {
         &DieType::D100 => 100,
         &DieType::Fate => 1,
         &DieType::Other => 0,
+        &DieType::Symbolic => 1,
     }
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+/// Draw a face of a Fate die: plus, blank or minus
+pub fn fate_symbol(value: i16) -> &'static str {
+    if value > 0 {
+        "+"
+    } else if value < 0 {
+        "\u{2212}"
+    } else {
+        ""
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Die {
     /// Unique identifier of the die
     pub id: String,
@@ -58,9 +76,23 @@ pub struct Die {
     /// The type of die (e.g. d20, d100)
     pub die: DieType,
 
+    /// If the die is a critical hit, by default when it rolls its highest face
+    #[serde(default)]
+    pub is_critical: bool,
+
     /// If the die is dropped in the final roll
     pub is_dropped: bool,
 
+    /// If the die exploded, its bonus die will be the child
+    pub is_exploded: bool,
+
+    /// If the die met the failure threshold of a dice pool
+    pub is_failed: bool,
+
+    /// If the die is a fumble, by default when it rolls its lowest face
+    #[serde(default)]
+    pub is_fumble: bool,
+
     /// If the die is dropped in the final roll
     pub is_rerolled: bool,
 
@@ -73,9 +105,20 @@ pub struct Die {
     /// Minimum number to roll
     pub min: i16,
 
+    /// Each value rolled for the die when it compounds or penetrates, in order
+    pub partials: Vec<i16>,
+
     /// Custom sides
     pub sides: Option<Vec<i16>>,
 
+    /// How the rolled face is drawn, for dice whose faces aren't numbers (e.g. "+" on a Fate die)
+    #[serde(default)]
+    pub symbol: Option<String>,
+
+    /// How many of each symbol the rolled face shows on a symbolic die (e.g. { "success": 2 })
+    #[serde(default)]
+    pub symbols: Option<BTreeMap<String, u16>>,
+
     /// Timestamp of the roll
     pub timestamp: DateTime<Utc>,
 
@@ -89,17 +132,33 @@ impl Die {
             id: Uuid::new_v4().to_string(),
             child: None,
             die,
+            is_critical: false,
             is_dropped: false,
+            is_exploded: false,
+            is_failed: false,
+            is_fumble: false,
             is_rerolled: false,
             is_successful: false,
             max: get_die_max(&die),
             min: get_die_min(&die),
+            partials: vec![],
             sides: None,
+            symbol: None,
+            symbols: None,
             timestamp: Utc::now(),
             value: 0,
         }
     }
 
+    /// Create a new die with the same type and faces as another die
+    pub fn like(die: &Die) -> Die {
+        let mut new = Die::new(die.die);
+        new.set_min(die.min);
+        new.set_max(die.max);
+        new.sides = die.sides.clone();
+        new
+    }
+
     /// Drop the die from the final roll
     pub fn drop(&mut self) {
         self.is_dropped = true
@@ -116,26 +175,45 @@ impl Die {
         self.child = Some(id.to_owned());
     }
 
+    /// Link the bonus die rolled when this die exploded
+    pub fn exploded(&mut self, die: &Die) {
+        self.is_exploded = true;
+        self.child = Some(die.id.to_owned());
+    }
+
+    /// Roll the die again and add the result to its value, returning the new partial roll
+    pub fn compound<R: DiceRng + ?Sized>(&mut self, rng: &mut R) -> i16 {
+        if self.partials.is_empty() {
+            self.partials.push(self.value);
+        }
+        let mut extra = Die::like(self);
+        extra.roll(rng);
+        self.partials.push(extra.value);
+        self.value = self.value.saturating_add(extra.value);
+        self.is_exploded = true;
+        extra.value
+    }
+
     /// Roll the die, generating a random number and calculating any modifiers
-    pub fn roll(&mut self) -> &Die {
+    pub fn roll<R: DiceRng + ?Sized>(&mut self, rng: &mut R) -> &Die {
         // generate a random number
         match &self.sides {
             &Some(ref sides) => {
-                let between = Range::new(0, sides.len());
-                let mut rng = rand::thread_rng();
-                let idx = between.ind_sample(&mut rng);
+                let idx = rng.between(0, sides.len() as i16 - 1) as usize;
                 let roll = sides[idx];
                 self.value = roll;
                 self.is_successful = true;
             },
             &None => {
-                let between = Range::new(self.min, self.max);
-                let mut rng = rand::thread_rng();
-                let roll = between.ind_sample(&mut rng);
+                let roll = rng.between(self.min, self.max);
                 self.value = roll;
                 self.is_successful = true;
             }
         }
+        self.symbol = match self.die {
+            DieType::Fate => Some(fate_symbol(self.value).to_string()),
+            _ => None,
+        };
         self
     }
 
@@ -185,15 +263,16 @@ fn it_can_set_die_max() {
 
 #[test]
 fn it_can_roll_die() {
+    let mut rng = SeededRng::new(20);
     let mut die = Die::new(DieType::D20);
-    die.roll();
+    die.roll(&mut rng);
     assert!(die.value >= 1);
     assert!(die.value <= 20);
 
     let mut custom = Die::new(DieType::Other);
     custom.set_max(-5);
     custom.set_min(-8);
-    custom.roll();
+    custom.roll(&mut rng);
     assert!(custom.value >= -8);
     assert!(custom.value <= -5);
 }
@@ -202,7 +281,100 @@ fn it_can_roll_die() {
 fn it_can_roll_custom_sides() {
     let mut die = Die::new(DieType::Other);
     die.sides = Some(vec![2, 4, 6, 8, 10]);
-    die.roll();
+    die.roll(&mut SeededRng::new(2));
     assert_ne!(die.value, 0);
     assert_eq!(die.value % 2, 0);
 }
+
+#[test]
+fn it_can_roll_the_highest_and_lowest_faces() {
+    let mut rng = SeededRng::new(4);
+    let mut die = Die::new(DieType::D4);
+    let mut values = vec![];
+    for _ in 0..1000 {
+        die.roll(&mut rng);
+        values.push(die.value);
+    }
+    assert!(values.contains(&1));
+    assert!(values.contains(&4));
+
+    let mut die = Die::new(DieType::Other);
+    die.set_min(3);
+    die.set_max(3);
+    die.roll(&mut rng);
+    assert_eq!(die.value, 3);
+}
+
+#[test]
+fn it_can_roll_with_a_fixed_sequence() {
+    let mut rng = FixedRng::new(vec![20, 1, 2]);
+    let mut die = Die::new(DieType::D20);
+    assert_eq!(die.roll(&mut rng).value, 20);
+    assert_eq!(die.roll(&mut rng).value, 1);
+
+    // Custom sides are picked by index
+    die.sides = Some(vec![10, 20, 30]);
+    assert_eq!(die.roll(&mut rng).value, 30);
+}
+
+#[test]
+fn it_can_draw_fate_dice() {
+    let mut rng = FixedRng::new(vec![1, 0, -1]);
+    let mut die = Die::new(DieType::Fate);
+    assert_eq!(die.roll(&mut rng).symbol, Some("+".to_string()));
+    assert_eq!(die.roll(&mut rng).symbol, Some("".to_string()));
+    assert_eq!(die.roll(&mut rng).symbol, Some("\u{2212}".to_string()));
+
+    let mut d6 = Die::new(DieType::D6);
+    assert_eq!(d6.roll(&mut rng).symbol, None);
+}
+
+/// Roll a die many times and compare how often each face comes up against a fair die, returning
+/// the chi-square statistic and the critical value it must stay under
+#[cfg(test)]
+fn chi_square<R: DiceRng>(die: &mut Die, rolls_per_face: u32, rng: &mut R) -> (f64, f64) {
+    let faces = (die.max - die.min + 1) as u32;
+    let mut counts: HashMap<i16, u32> = HashMap::new();
+    for _ in 0..(faces * rolls_per_face) {
+        die.roll(rng);
+        assert!(die.value >= die.min && die.value <= die.max, "{:?} rolled {}", die.die, die.value);
+        *counts.entry(die.value).or_insert(0) += 1;
+    }
+
+    let expected = rolls_per_face as f64;
+    let statistic = (die.min..(die.max + 1)).fold(0.0, |sum, face| {
+        let observed = *counts.get(&face).unwrap_or(&0) as f64;
+        sum + (observed - expected).powi(2) / expected
+    });
+
+    // Wilson-Hilferty approximation of the chi-square distribution at p = 0.00001, so a fair die
+    // should practically never fail
+    let k = (faces - 1) as f64;
+    let z = 4.265;
+    let critical = k * (1.0 - 2.0 / (9.0 * k) + z * (2.0 / (9.0 * k)).sqrt()).powi(3);
+    (statistic, critical)
+}
+
+#[test]
+fn it_rolls_every_die_type_fairly() {
+    let mut dice = vec![
+        Die::new(DieType::D4),
+        Die::new(DieType::D6),
+        Die::new(DieType::D8),
+        Die::new(DieType::D10),
+        Die::new(DieType::D12),
+        Die::new(DieType::D20),
+        Die::new(DieType::D100),
+        Die::new(DieType::Fate),
+    ];
+    let mut custom = Die::new(DieType::Other);
+    custom.set_min(-3);
+    custom.set_max(3);
+    dice.push(custom);
+
+    let mut rng = SeededRng::new(1337);
+    for die in dice.iter_mut() {
+        let (statistic, critical) = chi_square(die, 1000, &mut rng);
+        assert!(statistic < critical, "{:?} is not fair: chi-square {} >= {}", die.die, statistic, critical);
+    }
+}
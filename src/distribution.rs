@@ -0,0 +1,547 @@
+use command::Command;
+use error::RollError;
+use expression::{Expression, Function, Operator, Rounding};
+use roll::{compare, RollFlags, MAX_DICE};
+use std::collections::BTreeMap;
+
+/// Probability of each possible result
+pub type Pmf = BTreeMap<i32, f64>;
+
+/// Most combinations of faces to enumerate when keeping or dropping dice
+pub const MAX_COMBINATIONS: f64 = 250000.0;
+
+/// Most face counts to look at when enumerating combinations, every combination has one per face
+pub const MAX_FACE_COUNTS: f64 = 20000000.0;
+
+/// Most pairs of results to combine for a single operation in the equation
+pub const MAX_PAIRS: usize = 4000000;
+
+/// Most pairs of results to combine while adding up a group of dice one die at a time
+pub const MAX_GROUP_PAIRS: f64 = 20000000.0;
+
+/// Percentiles reported for every distribution
+const PERCENTILES: [u8; 7] = [5, 10, 25, 50, 75, 90, 95];
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Outcome {
+    /// Probability of rolling at least this value
+    pub at_least: f64,
+
+    /// Probability of rolling exactly this value
+    pub probability: f64,
+
+    pub value: i32,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Percentile {
+    pub percentile: u8,
+
+    /// Smallest result at or below which the percentage of rolls falls
+    pub value: i32,
+}
+
+/// The exact probability distribution of a roll's result
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Distribution {
+    pub max: i32,
+    pub mean: f64,
+    pub min: i32,
+
+    /// Every possible result, lowest first
+    pub outcomes: Vec<Outcome>,
+
+    pub percentiles: Vec<Percentile>,
+    pub std_dev: f64,
+    pub variance: f64,
+}
+
+impl Distribution {
+    /// Summarise a probability mass function
+    pub fn new(pmf: &Pmf) -> Distribution {
+        let mean = pmf.iter().fold(0.0, |sum, (&v, &p)| sum + v as f64 * p);
+        let variance = pmf.iter().fold(0.0, |sum, (&v, &p)| sum + (v as f64 - mean).powi(2) * p);
+
+        let mut outcomes = vec![];
+        let mut at_least: f64 = 1.0;
+        for (&value, &probability) in pmf.iter() {
+            outcomes.push(Outcome {
+                at_least: at_least.max(0.0).min(1.0),
+                probability,
+                value,
+            });
+            at_least -= probability;
+        }
+
+        let percentiles = PERCENTILES.iter().map(|&percentile| {
+            let target = percentile as f64 / 100.0;
+            let mut cumulative = 0.0;
+            let mut value = *pmf.keys().next_back().unwrap_or(&0);
+            for (&v, &p) in pmf.iter() {
+                cumulative += p;
+                // Allow for rounding errors in the cumulative sum
+                if cumulative >= target - 1e-9 {
+                    value = v;
+                    break;
+                }
+            }
+            Percentile { percentile, value }
+        }).collect();
+
+        Distribution {
+            max: *pmf.keys().next_back().unwrap_or(&0),
+            mean,
+            min: *pmf.keys().next().unwrap_or(&0),
+            outcomes,
+            percentiles,
+            std_dev: variance.sqrt(),
+            variance,
+        }
+    }
+
+    /// Probability of rolling at least a value
+    pub fn at_least(&self, value: i32) -> f64 {
+        self.outcomes.iter().filter(|o| o.value >= value).fold(0.0, |sum, o| sum + o.probability)
+    }
+}
+
+/// Compute the exact distribution of a parsed command without rolling it
+///
+/// Fails when the command cannot be computed exactly, such as exploding dice, rerolls that can never
+/// stop, or equations too large to enumerate.
+pub fn distribution(command: &Command, rounding: Rounding) -> Result<Distribution, RollError> {
+    let mut groups = vec![];
+    for flags in command.groups.iter() {
+        groups.push(group_pmf(flags)?);
+    }
+    let pmf = expression_pmf(&command.expression, &groups, rounding)?;
+    Ok(Distribution::new(&pmf))
+}
+
+/// Error for a command with too many outcomes to work out exactly
+fn too_large() -> RollError {
+    RollError::LimitExceeded {
+        offset: 0,
+        message: "Too many outcomes to compute exactly, try simulating the roll instead".to_string(),
+    }
+}
+
+/// Error for an equation that can't be evaluated for some outcome
+fn undefined() -> RollError {
+    RollError::Evaluate {
+        offset: 0,
+        message: "Some outcomes divide by zero or overflow".to_string(),
+    }
+}
+
+/// Probability of each face of a single die, after any rerolls
+fn face_pmf(flags: &RollFlags) -> Result<BTreeMap<i16, f64>, RollError> {
+    let mut faces = BTreeMap::new();
+    match flags.sides {
+        Some(ref sides) => {
+            if sides.is_empty() {
+                return Err(RollError::InvalidRange { offset: 0, message: "A die needs at least one side".to_string() });
+            }
+            for side in sides.iter() {
+                *faces.entry(*side).or_insert(0.0) += 1.0 / sides.len() as f64;
+            }
+        },
+        None => {
+            if flags.min > flags.max {
+                return Err(RollError::InvalidRange { offset: 0, message: "A die needs at least one side".to_string() });
+            }
+            let count = (flags.max as i32 - flags.min as i32 + 1) as f64;
+            for face in (flags.min as i32)..(flags.max as i32 + 1) {
+                faces.insert(face as i16, 1.0 / count);
+            }
+        },
+    }
+
+    let fresh = faces.clone();
+
+    // Rerolling forever leaves only the faces that do not match
+    if let Some(ref op) = flags.rr_op {
+        let kept: f64 = faces.iter().filter(|&(&v, _)| !compare(op, v, flags.rr)).map(|(_, &p)| p).sum();
+        if kept <= 0.0 {
+            return Err(RollError::InvalidRange { offset: 0, message: "Every face would be rerolled forever".to_string() });
+        }
+        faces = faces.into_iter()
+            .filter(|&(v, _)| !compare(op, v, flags.rr))
+            .map(|(v, p)| (v, p / kept))
+            .collect();
+    }
+
+    // Rerolling once replaces a matching face with a fresh roll, which `Roll::new` doesn't reroll
+    // forever again
+    if let Some(ref op) = flags.ro_op {
+        let rerolled: f64 = faces.iter().filter(|&(&v, _)| compare(op, v, flags.ro)).map(|(_, &p)| p).sum();
+        let mut rerolls: BTreeMap<i16, f64> = fresh.iter()
+            .filter(|_| rerolled > 0.0)
+            .map(|(&v, &p)| (v, rerolled * p))
+            .collect();
+        for (v, p) in faces.into_iter() {
+            if !compare(op, v, flags.ro) {
+                *rerolls.entry(v).or_insert(0.0) += p;
+            }
+        }
+        faces = rerolls;
+    }
+
+    Ok(faces)
+}
+
+/// If a die is kept by the gt/gte/lt/lte comparisons, mirroring `Roll::keep_greater_than` and friends
+fn kept_by_comparisons(flags: &RollFlags, value: i16) -> bool {
+    (flags.gt == 0 || (value as u16) > flags.gt) &&
+        (flags.gte == 0 || (value as u16) >= flags.gte) &&
+        (flags.lt == 0 || (value as u16) < flags.lt) &&
+        (flags.lte == 0 || (value as u16) <= flags.lte)
+}
+
+/// What a single kept die adds to the result: its value, or its successes in a dice pool
+fn contribution(flags: &RollFlags, value: i16) -> i32 {
+    match flags.success_op {
+        Some(ref op) => {
+            if compare(op, value, flags.success) {
+                match flags.double_op {
+                    Some(ref double) if compare(double, value, flags.double) => 2,
+                    _ => 1,
+                }
+            } else {
+                match flags.failure_op {
+                    Some(ref failure) if compare(failure, value, flags.failure) => -1,
+                    _ => 0,
+                }
+            }
+        },
+        None => value as i32,
+    }
+}
+
+/// Exact distribution of a single group of dice
+pub fn group_pmf(flags: &RollFlags) -> Result<Pmf, RollError> {
+    if flags.explode_op.is_some() {
+        return Err(RollError::UnsupportedArgument {
+            offset: 0,
+            message: "Exploding dice have no upper bound, try simulating the roll instead".to_string(),
+        });
+    }
+    if flags.n < 0 || flags.n > MAX_DICE {
+        return Err(too_large());
+    }
+
+    let faces = face_pmf(flags)?;
+    let n = flags.n as usize;
+    let keeps_by_order = flags.kh != 0 || flags.kl != 0 || flags.dh != 0 || flags.dl != 0;
+    let pmf = if keeps_by_order {
+        ordered_pmf(flags, &faces, n)?
+    } else {
+        independent_pmf(flags, &faces, n)?
+    };
+
+    // Shift by any modifiers TTML attached to the group
+    let modifier: i32 = flags.modifiers.iter().map(|m| *m as i32).sum();
+    Ok(pmf.into_iter().map(|(v, p)| (v + modifier, p)).collect())
+}
+
+/// Add up dice that are each kept or dropped on their own, one die at a time
+fn independent_pmf(flags: &RollFlags, faces: &BTreeMap<i16, f64>, n: usize) -> Result<Pmf, RollError> {
+    let mut die: Pmf = BTreeMap::new();
+    for (&face, &p) in faces.iter() {
+        let value = if kept_by_comparisons(flags, face) { contribution(flags, face) } else { 0 };
+        *die.entry(value).or_insert(0.0) += p;
+    }
+
+    // After i dice there are at most i * span + 1 totals, each combined with every result of the next die
+    let span = match (die.keys().next(), die.keys().next_back()) {
+        (Some(&min), Some(&max)) => max as f64 - min as f64,
+        _ => 0.0,
+    };
+    let pairs = (0..n).fold(0.0, |sum, i| sum + (i as f64 * span + 1.0).min(MAX_PAIRS as f64) * die.len() as f64);
+    if pairs > MAX_GROUP_PAIRS {
+        return Err(too_large());
+    }
+
+    let mut pmf: Pmf = BTreeMap::new();
+    pmf.insert(0, 1.0);
+    for _ in 0..n {
+        pmf = combine(&pmf, &die, |a, b| a.checked_add(b))?;
+    }
+    Ok(pmf)
+}
+
+/// Enumerate every combination of faces when dice are kept or dropped by how they rank
+fn ordered_pmf(flags: &RollFlags, faces: &BTreeMap<i16, f64>, n: usize) -> Result<Pmf, RollError> {
+    // Highest face first
+    let values: Vec<i16> = faces.keys().rev().cloned().collect();
+    let probabilities: Vec<f64> = values.iter().map(|v| faces[v]).collect();
+
+    // There are (n + k - 1) choose (k - 1) ways to roll n dice with k faces, ignoring order
+    let k = values.len();
+    let mut combinations = 1.0;
+    for i in 1..k {
+        combinations = combinations * (n + i) as f64 / i as f64;
+        if combinations > MAX_COMBINATIONS || combinations * k as f64 > MAX_FACE_COUNTS {
+            return Err(too_large());
+        }
+    }
+
+    let mut ln_factorials = vec![0.0; n + 1];
+    for i in 1..(n + 1) {
+        ln_factorials[i] = ln_factorials[i - 1] + (i as f64).ln();
+    }
+
+    let mut pmf: Pmf = BTreeMap::new();
+    enumerate(k, n, |counts: &[usize]| {
+        // Multinomial probability of rolling these counts of each face
+        let mut ln_p = ln_factorials[n];
+        for (i, &count) in counts.iter().enumerate() {
+            if count > 0 {
+                ln_p += count as f64 * probabilities[i].ln() - ln_factorials[count];
+            }
+        }
+
+        let mut dice: Vec<i16> = vec![];
+        for (i, &count) in counts.iter().enumerate() {
+            for _ in 0..count {
+                if kept_by_comparisons(flags, values[i]) {
+                    dice.push(values[i]);
+                }
+            }
+        }
+
+        // Apply keep and drop in the same order as `Roll::new`, dice are sorted highest first
+        if flags.kh != 0 {
+            dice.truncate(flags.kh.max(0) as usize);
+        }
+        if flags.kl != 0 {
+            let skip = dice.len().saturating_sub(flags.kl.max(0) as usize);
+            dice = dice.split_off(skip);
+        }
+        if flags.dh != 0 {
+            let skip = (flags.dh.max(0) as usize).min(dice.len());
+            dice = dice.split_off(skip);
+        }
+        if flags.dl != 0 {
+            let keep = dice.len().saturating_sub(flags.dl.max(0) as usize);
+            dice.truncate(keep);
+        }
+
+        let value = dice.iter().fold(0, |sum, &v| sum + contribution(flags, v));
+        *pmf.entry(value).or_insert(0.0) += ln_p.exp();
+    });
+
+    Ok(pmf)
+}
+
+/// Call `visit` with every way of splitting `n` dice between `k` faces, as a count for each face
+fn enumerate<F: FnMut(&[usize])>(k: usize, n: usize, mut visit: F) {
+    // Start with every die on the last face
+    let mut counts = vec![0; k];
+    counts[k - 1] = n;
+    loop {
+        visit(&counts);
+
+        // Move one die from the last face that has any to the face before it, and put the rest of
+        // that face's dice back on the last face
+        let j = match counts.iter().rposition(|&count| count > 0) {
+            Some(j) if j > 0 => j,
+            _ => return,
+        };
+        let rest = counts[j] - 1;
+        counts[j] = 0;
+        counts[j - 1] += 1;
+        counts[k - 1] = rest;
+    }
+}
+
+/// Combine two independent distributions, `None` from the operation means the result is undefined
+fn combine<F: Fn(i32, i32) -> Option<i32>>(left: &Pmf, right: &Pmf, operation: F) -> Result<Pmf, RollError> {
+    if left.len() * right.len() > MAX_PAIRS {
+        return Err(too_large());
+    }
+    let mut pmf: Pmf = BTreeMap::new();
+    for (&a, &pa) in left.iter() {
+        for (&b, &pb) in right.iter() {
+            *pmf.entry(operation(a, b).ok_or_else(undefined)?).or_insert(0.0) += pa * pb;
+        }
+    }
+    Ok(pmf)
+}
+
+/// Exact distribution of an equation, given the distribution of each dice group it refers to
+fn expression_pmf(expression: &Expression, groups: &[Pmf], rounding: Rounding) -> Result<Pmf, RollError> {
+    match expression {
+        &Expression::Number { value } => {
+            let mut pmf = BTreeMap::new();
+            pmf.insert(value, 1.0);
+            Ok(pmf)
+        },
+        &Expression::Roll { index, .. } => groups.get(index).cloned().ok_or_else(undefined),
+        &Expression::Negate { ref operand, .. } => {
+            let pmf = expression_pmf(operand, groups, rounding)?;
+            Ok(pmf.into_iter().map(|(v, p)| (-v, p)).collect())
+        },
+        &Expression::Operation { operator, ref left, ref right, .. } => {
+            let left = expression_pmf(left, groups, rounding)?;
+            let right = expression_pmf(right, groups, rounding)?;
+            match operator {
+                Operator::Add => combine(&left, &right, |a, b| a.checked_add(b)),
+                Operator::Subtract => combine(&left, &right, |a, b| a.checked_sub(b)),
+                Operator::Multiply => combine(&left, &right, |a, b| a.checked_mul(b)),
+                Operator::Divide => combine(&left, &right, |a, b| rounding.divide(a, b)),
+            }
+        },
+        &Expression::Function { function, ref arguments, .. } => {
+            let inner = match function {
+                Function::Ceil => Rounding::Ceil,
+                Function::Floor => Rounding::Floor,
+                Function::Round => Rounding::Round,
+                _ => rounding,
+            };
+            let mut pmfs = vec![];
+            for argument in arguments.iter() {
+                pmfs.push(expression_pmf(argument, groups, inner)?);
+            }
+            let mut pmf = pmfs.remove(0);
+            match function {
+                Function::Abs => {
+                    let mut absolute = BTreeMap::new();
+                    for (v, p) in pmf.into_iter() {
+                        *absolute.entry(v.checked_abs().ok_or_else(undefined)?).or_insert(0.0) += p;
+                    }
+                    pmf = absolute;
+                },
+                Function::Max => {
+                    for other in pmfs.iter() {
+                        pmf = combine(&pmf, other, |a, b| Some(a.max(b)))?;
+                    }
+                },
+                Function::Min => {
+                    for other in pmfs.iter() {
+                        pmf = combine(&pmf, other, |a, b| Some(a.min(b)))?;
+                    }
+                },
+                _ => {},
+            }
+            Ok(pmf)
+        },
+        &Expression::Group { ref expression, .. } => expression_pmf(expression, groups, rounding),
+    }
+}
+
+#[cfg(test)]
+fn distribution_of(command: &str) -> Distribution {
+    use command;
+    distribution(&command::parse(command).unwrap(), Rounding::Floor).unwrap()
+}
+
+#[cfg(test)]
+fn assert_close(a: f64, b: f64) {
+    assert!((a - b).abs() < 1e-9, "{} != {}", a, b);
+}
+
+#[test]
+fn it_can_compute_simple_distributions() {
+    let d6 = distribution_of("1d6");
+    assert_eq!(d6.outcomes.len(), 6);
+    assert_close(d6.mean, 3.5);
+    assert_close(d6.variance, 35.0 / 12.0);
+    assert_eq!(d6.min, 1);
+    assert_eq!(d6.max, 6);
+
+    let two_d6 = distribution_of("2d6");
+    assert_close(two_d6.outcomes.iter().find(|o| o.value == 7).unwrap().probability, 1.0 / 6.0);
+    assert_close(two_d6.at_least(12), 1.0 / 36.0);
+    assert_eq!(two_d6.percentiles.iter().find(|p| p.percentile == 50).unwrap().value, 7);
+
+    let modified = distribution_of("1d20+5");
+    assert_eq!(modified.min, 6);
+    assert_eq!(modified.max, 25);
+    assert_close(modified.at_least(21), 0.25);
+}
+
+#[test]
+fn it_can_compute_keep_and_drop_distributions() {
+    let advantage = distribution_of("2d20kh1");
+    assert_close(advantage.outcomes.iter().find(|o| o.value == 20).unwrap().probability, 1.0 - (19.0f64 / 20.0).powi(2));
+
+    let stats = distribution_of("4d6kh3");
+    assert_close(stats.mean, 15869.0 / 1296.0);
+    let dropped = distribution_of("4d6dl1");
+    assert_close(dropped.mean, stats.mean);
+
+    let total: f64 = stats.outcomes.iter().map(|o| o.probability).sum();
+    assert_close(total, 1.0);
+}
+
+#[test]
+fn it_can_compute_reroll_distributions() {
+    let once = distribution_of("1d6ro<=1");
+    assert_close(once.outcomes[0].probability, 1.0 / 36.0);
+
+    let forever = distribution_of("1d6rr<=2");
+    assert_eq!(forever.min, 3);
+    assert_close(forever.mean, 4.5);
+
+    assert!(::command::parse("1d6rr<=6").is_err());
+
+    // The die rolled once isn't rerolled forever, so a 1 can still come up
+    let both = distribution_of("1d6rr<=1ro<=2");
+    assert_close(both.outcomes[0].probability, 0.2 * 1.0 / 6.0);
+    assert_close(both.outcomes.iter().find(|o| o.value == 2).unwrap().probability, 0.2 * 1.0 / 6.0);
+    assert_close(both.outcomes.iter().find(|o| o.value == 6).unwrap().probability, 0.2 + 0.2 / 6.0);
+}
+
+#[test]
+fn it_can_compute_equation_distributions() {
+    let doubled = distribution_of("1d4*2");
+    let values: Vec<i32> = doubled.outcomes.iter().map(|o| o.value).collect();
+    assert_eq!(values, vec![2, 4, 6, 8]);
+
+    let halved = distribution_of("ceil(1d4/2)");
+    let values: Vec<i32> = halved.outcomes.iter().map(|o| o.value).collect();
+    assert_eq!(values, vec![1, 2]);
+
+    let best = distribution_of("max(1d20,1d20)");
+    let advantage = distribution_of("2d20kh1");
+    assert_close(best.mean, advantage.mean);
+
+    let custom = distribution_of("1d[1,1,2]");
+    assert_close(custom.outcomes[0].probability, 2.0 / 3.0);
+}
+
+#[test]
+fn it_can_compute_dice_pool_distributions() {
+    // Successes on 3d6 where 5 or 6 succeeds are binomial(3, 1/3)
+    let pool = distribution_of("3d6s>=5");
+    assert_close(pool.outcomes.iter().find(|o| o.value == 0).unwrap().probability, 8.0 / 27.0);
+    assert_close(pool.mean, 1.0);
+
+    let error = distribution(&::command::parse("3d6!").unwrap(), Rounding::Floor).unwrap_err();
+    assert!(error.message().starts_with("Exploding dice"));
+}
+
+#[test]
+fn it_limits_the_work_for_large_groups() {
+    let many = distribution_of("300d6");
+    assert_eq!(many.max, 1800);
+
+    let error = distribution(&::command::parse("1000d63").unwrap(), Rounding::Floor).unwrap_err();
+    assert!(error.message().starts_with("Too many outcomes"));
+}
+
+#[test]
+fn it_can_compute_distributions_of_large_dice() {
+    let largest = distribution_of("1d32767");
+    assert_eq!(largest.min, 1);
+    assert_eq!(largest.max, 32767);
+
+    // Keeping from no dice at all still walks every face without running out of stack
+    let none = distribution_of("0d30000kh1");
+    assert_eq!(none.outcomes.len(), 1);
+    assert_eq!(none.outcomes[0].value, 0);
+
+    let error = distribution(&::command::parse("2d30000kh1").unwrap(), Rounding::Floor).unwrap_err();
+    assert!(error.message().starts_with("Too many outcomes"));
+}
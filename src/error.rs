@@ -0,0 +1,97 @@
+use std::error::Error;
+use std::fmt;
+
+/// Why a command could not be rolled
+///
+/// Every error carries the byte offset into the command where the problem was found, along with a
+/// message that can be shown to whoever typed it.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum RollError {
+    /// The command is not a valid equation of dice, numbers and functions (e.g. "1d20+")
+    Parse { offset: usize, message: String },
+
+    /// The command uses something the API doesn't support (e.g. an unknown function)
+    UnsupportedArgument { offset: usize, message: String },
+
+    /// A number is outside the range that makes sense for it (e.g. a die with no sides)
+    InvalidRange { offset: usize, message: String },
+
+    /// The command is too large to roll (e.g. more dice than `MAX_DICE`)
+    LimitExceeded { offset: usize, message: String },
+
+    /// The dice were rolled but the equation could not be evaluated (e.g. dividing by zero)
+    Evaluate { offset: usize, message: String },
+
+    /// No random numbers were available to roll with
+    Random { offset: usize, message: String },
+
+    /// The roll could not be recorded in the roll history
+    Storage { offset: usize, message: String },
+}
+
+impl RollError {
+    /// Byte offset into the command where the error was found
+    pub fn offset(&self) -> usize {
+        match self {
+            &RollError::Parse { offset, .. } => offset,
+            &RollError::UnsupportedArgument { offset, .. } => offset,
+            &RollError::InvalidRange { offset, .. } => offset,
+            &RollError::LimitExceeded { offset, .. } => offset,
+            &RollError::Evaluate { offset, .. } => offset,
+            &RollError::Random { offset, .. } => offset,
+            &RollError::Storage { offset, .. } => offset,
+        }
+    }
+
+    /// Readable description of the error
+    pub fn message(&self) -> &str {
+        match self {
+            &RollError::Parse { ref message, .. } => message,
+            &RollError::UnsupportedArgument { ref message, .. } => message,
+            &RollError::InvalidRange { ref message, .. } => message,
+            &RollError::LimitExceeded { ref message, .. } => message,
+            &RollError::Evaluate { ref message, .. } => message,
+            &RollError::Random { ref message, .. } => message,
+            &RollError::Storage { ref message, .. } => message,
+        }
+    }
+
+    /// Move the error further into the command, used when a part of the command was parsed on its own
+    pub fn shift(self, by: usize) -> RollError {
+        self.map_offset(|offset| offset + by)
+    }
+
+    /// Point the error somewhere else, e.g. back at the command before anything was substituted into it
+    pub fn map_offset<F: Fn(usize) -> usize>(self, f: F) -> RollError {
+        match self {
+            RollError::Parse { offset, message } => RollError::Parse { offset: f(offset), message },
+            RollError::UnsupportedArgument { offset, message } => RollError::UnsupportedArgument { offset: f(offset), message },
+            RollError::InvalidRange { offset, message } => RollError::InvalidRange { offset: f(offset), message },
+            RollError::LimitExceeded { offset, message } => RollError::LimitExceeded { offset: f(offset), message },
+            RollError::Evaluate { offset, message } => RollError::Evaluate { offset: f(offset), message },
+            RollError::Random { offset, message } => RollError::Random { offset: f(offset), message },
+            RollError::Storage { offset, message } => RollError::Storage { offset: f(offset), message },
+        }
+    }
+}
+
+impl fmt::Display for RollError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} (at position {})", self.message(), self.offset())
+    }
+}
+
+impl Error for RollError {
+    fn description(&self) -> &str {
+        match self {
+            &RollError::Parse { .. } => "invalid command",
+            &RollError::UnsupportedArgument { .. } => "unsupported argument",
+            &RollError::InvalidRange { .. } => "invalid range",
+            &RollError::LimitExceeded { .. } => "limit exceeded",
+            &RollError::Evaluate { .. } => "invalid equation",
+            &RollError::Random { .. } => "no random numbers",
+            &RollError::Storage { .. } => "could not record roll",
+        }
+    }
+}
@@ -0,0 +1,258 @@
+use rocket;
+use rocket::http::RawStr;
+use serde_json;
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::RecvTimeoutError;
+use std::thread;
+use std::time::Duration;
+use v1::Rooms;
+
+/// Seconds a listener can go without a roll before a comment is sent, so clients that have gone away
+/// are noticed and give back their place
+const KEEP_ALIVE_SECS: u64 = 15;
+
+/// Seconds a client has to send its request
+const REQUEST_TIMEOUT_SECS: u64 = 10;
+
+/// Most bytes read from a request before the headers end
+const MAX_REQUEST_SIZE: u64 = 8192;
+
+/// Settings for the room event server, read from `Rocket.toml`
+#[derive(Clone, Debug)]
+pub struct Settings {
+    pub access_control_allow_origin: String,
+    pub address: String,
+
+    /// Most members listening at once across every room
+    pub max_listeners: usize,
+
+    /// Most members listening at once in a single room
+    pub max_room_listeners: usize,
+
+    /// Port events are served on, the API's port + 1 by default
+    pub port: u16,
+}
+
+impl Settings {
+    pub fn new(config: &rocket::Config) -> Settings {
+        Settings {
+            access_control_allow_origin: config.get_str("access_control_allow_origin").unwrap_or("http://localhost:3000").to_string(),
+            address: config.address.clone(),
+            max_listeners: config.get_int("max_listeners").unwrap_or(256) as usize,
+            max_room_listeners: config.get_int("max_room_listeners").unwrap_or(16) as usize,
+            port: config.get_int("events_port").map(|port| port as u16).unwrap_or(config.port + 1),
+        }
+    }
+}
+
+/// How many connections are open, overall and listening to each room
+struct Listeners {
+    rooms: HashMap<String, usize>,
+    total: usize,
+}
+
+/// A connection's place under the limits, given back when it closes
+struct Slot {
+    listeners: Arc<Mutex<Listeners>>,
+    room_id: Option<String>,
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        let mut listeners = self.listeners.lock().unwrap();
+        listeners.total -= 1;
+        if let Some(ref room_id) = self.room_id {
+            let remove = match listeners.rooms.get_mut(room_id) {
+                Some(count) => {
+                    *count -= 1;
+                    *count == 0
+                },
+                None => false,
+            };
+            if remove {
+                listeners.rooms.remove(room_id);
+            }
+        }
+    }
+}
+
+/// Serve room events on their own port, in the background
+///
+/// Each listener has its own thread instead of holding one of Rocket's workers, and every event is
+/// flushed as soon as it is rolled. Connections past `max_listeners`, or past `max_room_listeners`
+/// in a room, are turned away with a `503`.
+pub fn serve(rooms: Rooms, settings: Settings) -> io::Result<()> {
+    let server = TcpListener::bind((settings.address.as_str(), settings.port))?;
+    let listeners = Arc::new(Mutex::new(Listeners { rooms: HashMap::new(), total: 0 }));
+    thread::spawn(move || {
+        for stream in server.incoming() {
+            let mut stream = match stream {
+                Ok(stream) => stream,
+                Err(_) => continue,
+            };
+
+            // Count the connection before reading anything, so slow clients can't pile up threads
+            let slot = {
+                let mut counts = listeners.lock().unwrap();
+                if counts.total >= settings.max_listeners {
+                    None
+                } else {
+                    counts.total += 1;
+                    Some(Slot { listeners: listeners.clone(), room_id: None })
+                }
+            };
+            let slot = match slot {
+                Some(slot) => slot,
+                None => {
+                    let _ = respond(&mut stream, "503 Service Unavailable", "Too many listeners, try again later.", &settings);
+                    continue;
+                },
+            };
+
+            let rooms = rooms.clone();
+            let settings = settings.clone();
+            thread::spawn(move || {
+                let _ = listen(stream, slot, &rooms, &settings);
+            });
+        }
+    });
+    Ok(())
+}
+
+/// The method, path and member token of a request
+fn read_request(stream: &TcpStream) -> io::Result<(String, String, Option<String>)> {
+    let mut reader = BufReader::new(stream.take(MAX_REQUEST_SIZE));
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let (method, path) = {
+        let mut parts = line.split_whitespace();
+        (parts.next().unwrap_or("").to_string(), parts.next().unwrap_or("").to_string())
+    };
+
+    let mut token = None;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Request ended before its headers"));
+        }
+        let header = header.trim();
+        if header.is_empty() {
+            break;
+        }
+        if let Some(colon) = header.find(':') {
+            if header[..colon].trim().eq_ignore_ascii_case("X-Member-Token") {
+                token = Some(header[colon + 1..].trim().to_string());
+            }
+        }
+    }
+    Ok((method, path, token))
+}
+
+/// Member token from the `token` query parameter, since browsers' EventSource can't send headers
+fn query_token(path: &str) -> Option<String> {
+    let query = path.splitn(2, '?').nth(1)?;
+    query.split('&').filter_map(|pair| {
+        let mut parts = pair.splitn(2, '=');
+        match (parts.next(), parts.next()) {
+            (Some("token"), Some(value)) => RawStr::from_str(value).url_decode().ok(),
+            _ => None,
+        }
+    }).next()
+}
+
+/// Id of the room in a path like /v1/rooms/:id/events
+fn room_id(path: &str) -> Option<&str> {
+    let path = path.split('?').next().unwrap_or("");
+    let segments: Vec<&str> = path.split('/').collect();
+    match segments.as_slice() {
+        &["", "v1", "rooms", id, "events"] if !id.is_empty() => Some(id),
+        _ => None,
+    }
+}
+
+fn cors_headers(settings: &Settings) -> String {
+    format!(
+        "Access-Control-Allow-Origin: {}\r\nAccess-Control-Allow-Methods: GET, OPTIONS\r\nAccess-Control-Allow-Headers: X-Member-Token\r\nAccess-Control-Allow-Credentials: true\r\n",
+        settings.access_control_allow_origin,
+    )
+}
+
+/// Send an error in the same shape as the API's, then close the connection
+fn respond(stream: &mut TcpStream, status: &str, reason: &str, settings: &Settings) -> io::Result<()> {
+    let body = json!({ "status": "error", "reason": reason }).to_string();
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n{}Connection: close\r\n\r\n{}",
+        status, body.len(), cors_headers(settings), body,
+    );
+    stream.write_all(response.as_bytes())?;
+    stream.flush()
+}
+
+/// Send the rolls a member can see until they disconnect or the room is gone
+fn listen(mut stream: TcpStream, mut slot: Slot, rooms: &Rooms, settings: &Settings) -> io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(REQUEST_TIMEOUT_SECS)))?;
+    let (method, path, token) = read_request(&stream)?;
+
+    if method == "OPTIONS" {
+        let response = format!("HTTP/1.1 204 No Content\r\n{}Connection: close\r\n\r\n", cors_headers(settings));
+        stream.write_all(response.as_bytes())?;
+        return stream.flush();
+    }
+    let id = match (method.as_str(), room_id(&path)) {
+        ("GET", Some(id)) => id.to_string(),
+        _ => return respond(&mut stream, "404 Not Found", "Resource not found.", settings),
+    };
+    let token = match token.or_else(|| query_token(&path)) {
+        Some(token) => token,
+        None => return respond(&mut stream, "403 Forbidden", "Send the member's token in the X-Member-Token header or the token query parameter.", settings),
+    };
+
+    let receiver = {
+        let mut rooms = rooms.lock().unwrap();
+        let room = match rooms.get_mut(&id) {
+            Some(room) => room,
+            None => return respond(&mut stream, "404 Not Found", "Resource not found.", settings),
+        };
+        if room.authenticate(&token).is_none() {
+            return respond(&mut stream, "403 Forbidden", "Unknown member token.", settings);
+        }
+
+        let mut listeners = slot.listeners.lock().unwrap();
+        let count = listeners.rooms.entry(id.clone()).or_insert(0);
+        if *count >= settings.max_room_listeners {
+            drop(listeners);
+            return respond(&mut stream, "503 Service Unavailable", "Too many listeners in this room, try again later.", settings);
+        }
+        *count += 1;
+        slot.room_id = Some(id);
+        room.subscribe(&token)
+    };
+    let receiver = match receiver {
+        Some(receiver) => receiver,
+        None => return Ok(()),
+    };
+
+    // Sent straight away so the client knows it is connected
+    let head = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\n{}Connection: close\r\n\r\n: connected\n\n",
+        cors_headers(settings),
+    );
+    stream.write_all(head.as_bytes())?;
+    stream.flush()?;
+
+    loop {
+        match receiver.recv_timeout(Duration::from_secs(KEEP_ALIVE_SECS)) {
+            Ok(event) => {
+                let data = serde_json::to_string(&event).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                stream.write_all(format!("event: roll\ndata: {}\n\n", data).as_bytes())?;
+            },
+            Err(RecvTimeoutError::Timeout) => stream.write_all(b":\n\n")?,
+            Err(RecvTimeoutError::Disconnected) => return Ok(()),
+        }
+        stream.flush()?;
+    }
+}
+
@@ -0,0 +1,299 @@
+use roll::Roll;
+use std::fmt;
+
+/// Arithmetic operators that can join two parts of an equation
+#[derive(Copy, Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Operator {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+}
+
+/// Functions that can be called inside of an equation (e.g. max(1d20,1d20))
+#[derive(Copy, Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Function {
+    Abs,
+    Ceil,
+    Floor,
+    Max,
+    Min,
+    Round,
+}
+
+impl Function {
+    /// Find a function by the name used in a command
+    pub fn from_name(name: &str) -> Option<Function> {
+        match name {
+            "abs" => Some(Function::Abs),
+            "ceil" => Some(Function::Ceil),
+            "floor" => Some(Function::Floor),
+            "max" => Some(Function::Max),
+            "min" => Some(Function::Min),
+            "round" => Some(Function::Round),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            &Function::Abs => "abs",
+            &Function::Ceil => "ceil",
+            &Function::Floor => "floor",
+            &Function::Max => "max",
+            &Function::Min => "min",
+            &Function::Round => "round",
+        }
+    }
+}
+
+/// How the result of a division is rounded back to a whole number
+#[derive(Copy, Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Rounding {
+    Ceil,
+    Floor,
+    Round,
+    Truncate,
+}
+
+impl Rounding {
+    /// Find a rounding mode by name
+    pub fn from_name(name: &str) -> Option<Rounding> {
+        match name {
+            "ceil" => Some(Rounding::Ceil),
+            "floor" => Some(Rounding::Floor),
+            "round" => Some(Rounding::Round),
+            "truncate" => Some(Rounding::Truncate),
+            _ => None,
+        }
+    }
+
+    /// Divide two numbers, rounding the quotient
+    pub fn divide(&self, dividend: i32, divisor: i32) -> Option<i32> {
+        if divisor == 0 {
+            return None;
+        }
+        let quotient = dividend as f64 / divisor as f64;
+        let rounded = match self {
+            &Rounding::Ceil => quotient.ceil(),
+            &Rounding::Floor => quotient.floor(),
+            &Rounding::Round => quotient.round(),
+            &Rounding::Truncate => quotient.trunc(),
+        };
+        Some(rounded as i32)
+    }
+}
+
+impl Default for Rounding {
+    fn default() -> Rounding {
+        Rounding::Floor
+    }
+}
+
+/// A typed tree describing how the total of a roll is computed
+///
+/// Every node records the value it evaluated to, so clients can show each step of the math.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Expression {
+    /// A constant number
+    Number {
+        value: i32,
+    },
+
+    /// A group of dice, pointing at the group in `Roll.rolls`
+    Roll {
+        equation: String,
+        index: usize,
+        value: i32,
+    },
+
+    /// A negated expression (e.g. -1d4)
+    Negate {
+        operand: Box<Expression>,
+        value: i32,
+    },
+
+    /// Two expressions joined by an operator
+    Operation {
+        operator: Operator,
+        left: Box<Expression>,
+        right: Box<Expression>,
+        value: i32,
+    },
+
+    /// A function applied to one or more expressions
+    Function {
+        function: Function,
+        arguments: Vec<Expression>,
+        value: i32,
+    },
+
+    /// An expression wrapped in parentheses
+    Group {
+        expression: Box<Expression>,
+        value: i32,
+    },
+}
+
+impl Expression {
+    /// The value the expression evaluated to
+    pub fn value(&self) -> i32 {
+        match self {
+            &Expression::Number { value } => value,
+            &Expression::Roll { value, .. } => value,
+            &Expression::Negate { value, .. } => value,
+            &Expression::Operation { value, .. } => value,
+            &Expression::Function { value, .. } => value,
+            &Expression::Group { value, .. } => value,
+        }
+    }
+
+    /// Evaluate the expression against a set of rolled dice groups, recording the value of each node
+    ///
+    /// Divisions are rounded with `rounding` unless they sit inside a floor, ceil or round call,
+    /// in which case that function decides. Returns `None` on division by zero or overflow.
+    pub fn evaluate(&mut self, rolls: &[Roll], rounding: Rounding) -> Option<i32> {
+        let result = match self {
+            &mut Expression::Number { value } => value,
+            &mut Expression::Roll { index, ref mut value, .. } => {
+                *value = match rolls.get(index) {
+                    Some(roll) => roll.value,
+                    None => return None,
+                };
+                *value
+            },
+            &mut Expression::Negate { ref mut operand, ref mut value } => {
+                *value = operand.evaluate(rolls, rounding)?.checked_neg()?;
+                *value
+            },
+            &mut Expression::Operation { operator, ref mut left, ref mut right, ref mut value } => {
+                let l = left.evaluate(rolls, rounding)?;
+                let r = right.evaluate(rolls, rounding)?;
+                *value = match operator {
+                    Operator::Add => l.checked_add(r)?,
+                    Operator::Subtract => l.checked_sub(r)?,
+                    Operator::Multiply => l.checked_mul(r)?,
+                    Operator::Divide => rounding.divide(l, r)?,
+                };
+                *value
+            },
+            &mut Expression::Function { function, ref mut arguments, ref mut value } => {
+                let inner = match function {
+                    Function::Ceil => Rounding::Ceil,
+                    Function::Floor => Rounding::Floor,
+                    Function::Round => Rounding::Round,
+                    _ => rounding,
+                };
+                let mut values = vec![];
+                for argument in arguments.iter_mut() {
+                    values.push(argument.evaluate(rolls, inner)?);
+                }
+                *value = match function {
+                    Function::Abs => values[0].checked_abs()?,
+                    Function::Max => *values.iter().max()?,
+                    Function::Min => *values.iter().min()?,
+                    _ => values[0],
+                };
+                *value
+            },
+            &mut Expression::Group { ref mut expression, ref mut value } => {
+                *value = expression.evaluate(rolls, rounding)?;
+                *value
+            },
+        };
+        Some(result)
+    }
+
+    /// Constants added to or subtracted from the dice at the top level of the equation
+    ///
+    /// `1d20+5-1` has the modifiers `[5, -1]`, while `(2d6+3)*2` has none.
+    pub fn modifiers(&self) -> Vec<i32> {
+        let mut modifiers = vec![];
+        self.collect_modifiers(1, &mut modifiers);
+        modifiers
+    }
+
+    fn collect_modifiers(&self, sign: i32, modifiers: &mut Vec<i32>) {
+        match self {
+            &Expression::Number { value } => modifiers.push(value * sign),
+            &Expression::Operation { operator: Operator::Add, ref left, ref right, .. } => {
+                left.collect_modifiers(sign, modifiers);
+                right.collect_modifiers(sign, modifiers);
+            },
+            &Expression::Operation { operator: Operator::Subtract, ref left, ref right, .. } => {
+                left.collect_modifiers(sign, modifiers);
+                right.collect_modifiers(-sign, modifiers);
+            },
+            _ => {},
+        }
+    }
+}
+
+impl fmt::Display for Expression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &Expression::Number { value } => write!(f, "{}", value),
+            &Expression::Roll { ref equation, .. } => write!(f, "{}", equation),
+            &Expression::Negate { ref operand, .. } => write!(f, "-{}", operand),
+            &Expression::Operation { operator, ref left, ref right, .. } => {
+                let symbol = match operator {
+                    Operator::Add => "+",
+                    Operator::Subtract => "-",
+                    Operator::Multiply => "*",
+                    Operator::Divide => "/",
+                };
+                write!(f, "{} {} {}", left, symbol, right)
+            },
+            &Expression::Function { function, ref arguments, .. } => {
+                let arguments: Vec<String> = arguments.iter().map(|a| a.to_string()).collect();
+                write!(f, "{}({})", function.name(), arguments.join(", "))
+            },
+            &Expression::Group { ref expression, .. } => write!(f, "({})", expression),
+        }
+    }
+}
+
+#[test]
+fn it_can_round_divisions() {
+    assert_eq!(Rounding::Floor.divide(7, 2), Some(3));
+    assert_eq!(Rounding::Ceil.divide(7, 2), Some(4));
+    assert_eq!(Rounding::Round.divide(5, 2), Some(3));
+    assert_eq!(Rounding::Floor.divide(-7, 2), Some(-4));
+    assert_eq!(Rounding::Truncate.divide(-7, 2), Some(-3));
+    assert_eq!(Rounding::Floor.divide(1, 0), None);
+}
+
+#[test]
+fn it_can_evaluate_expressions() {
+    // (7 / 2) * 2 - max(1, 4)
+    let mut expression = Expression::Operation {
+        operator: Operator::Subtract,
+        left: Box::new(Expression::Operation {
+            operator: Operator::Multiply,
+            left: Box::new(Expression::Group {
+                expression: Box::new(Expression::Operation {
+                    operator: Operator::Divide,
+                    left: Box::new(Expression::Number { value: 7 }),
+                    right: Box::new(Expression::Number { value: 2 }),
+                    value: 0,
+                }),
+                value: 0,
+            }),
+            right: Box::new(Expression::Number { value: 2 }),
+            value: 0,
+        }),
+        right: Box::new(Expression::Function {
+            function: Function::Max,
+            arguments: vec![Expression::Number { value: 1 }, Expression::Number { value: 4 }],
+            value: 0,
+        }),
+        value: 0,
+    };
+    assert_eq!(expression.evaluate(&[], Rounding::Floor), Some(2));
+    assert_eq!(expression.evaluate(&[], Rounding::Ceil), Some(4));
+    assert_eq!(expression.to_string(), "(7 / 2) * 2 - max(1, 4)");
+}
@@ -0,0 +1,205 @@
+use chrono::DateTime;
+use chrono::prelude::Utc;
+use command;
+use expression::Rounding;
+use hex;
+use rand::{OsRng, Rng};
+use rng::SeededRng;
+use roll::Roll;
+use sha2::{Digest, Sha256};
+use std::io;
+use uuid::Uuid;
+
+/// A server seed that has been committed to before any dice were rolled with it
+///
+/// The commitment is the SHA-256 hash of the server seed. Every roll combines the server seed with
+/// a seed chosen by the client and a nonce, so neither side can pick the dice on their own. Once the
+/// server seed is revealed, anyone can check the commitment and re-roll every die.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Commitment {
+    /// Unique identifier of the commitment
+    pub id: String,
+
+    /// SHA-256 hash of the server seed, published up front
+    pub commitment: String,
+
+    /// If the server seed has been revealed, no more dice can be rolled with it
+    pub is_revealed: bool,
+
+    /// Number of rolls made with the commitment, used as the nonce of the next roll
+    pub nonce: u64,
+
+    /// Secret seed chosen by the server
+    pub server_seed: String,
+
+    /// Timestamp of the commitment
+    pub timestamp: DateTime<Utc>,
+}
+
+impl Commitment {
+    /// Commit to a new random server seed
+    pub fn new() -> io::Result<Commitment> {
+        let mut bytes = [0u8; 32];
+        OsRng::new()?.fill_bytes(&mut bytes);
+        let server_seed = hex::encode(&bytes[..]);
+        Ok(Commitment {
+            id: Uuid::new_v4().to_string(),
+            commitment: commit(&server_seed),
+            is_revealed: false,
+            nonce: 0,
+            server_seed,
+            timestamp: Utc::now(),
+        })
+    }
+
+    /// Take the seed for the next roll, returning the nonce it used and the seed
+    pub fn next_seed(&mut self, client_seed: &str) -> Option<(u64, u64)> {
+        if self.is_revealed {
+            return None;
+        }
+        let nonce = self.nonce;
+        self.nonce += 1;
+        Some((nonce, roll_seed(&self.server_seed, client_seed, nonce)))
+    }
+
+    /// Reveal the server seed, after which no more dice can be rolled with it
+    pub fn reveal(&mut self) -> &str {
+        self.is_revealed = true;
+        &self.server_seed
+    }
+}
+
+/// Everything needed to check a roll made with a commitment once the server seed is revealed
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Proof {
+    /// Unique identifier of the commitment
+    pub commitment_id: String,
+
+    /// SHA-256 hash of the server seed
+    pub commitment: String,
+
+    /// Seed chosen by the client
+    pub client_seed: String,
+
+    /// Nonce of the roll within the commitment
+    pub nonce: u64,
+
+    /// How divisions in the command were rounded
+    #[serde(default)]
+    pub rounding: Rounding,
+}
+
+/// Hash a server seed into the commitment that is published before rolling
+pub fn commit(server_seed: &str) -> String {
+    let mut hasher = Sha256::default();
+    hasher.input(server_seed.as_bytes());
+    hex::encode(hasher.result().as_slice())
+}
+
+/// Combine the server seed, client seed and nonce into the seed the dice are rolled with
+///
+/// Dice are rolled from a 64-bit seed, so only the first 8 bytes of the SHA-256 hash are used. The
+/// server seed stays 256 bits, so the seed of a roll still can't be guessed before it is revealed.
+pub fn roll_seed(server_seed: &str, client_seed: &str, nonce: u64) -> u64 {
+    let mut hasher = Sha256::default();
+    hasher.input(server_seed.as_bytes());
+    hasher.input(b":");
+    hasher.input(client_seed.as_bytes());
+    hasher.input(b":");
+    hasher.input(nonce.to_string().as_bytes());
+    let hash = hasher.result();
+    hash.as_slice()[..8].iter().fold(0, |seed, byte| (seed << 8) | *byte as u64)
+}
+
+/// Check a roll against a revealed server seed
+///
+/// The server seed must match the commitment, and rolling the command again with the combined
+/// seed and the proof's rounding must give exactly the same dice, equation and total.
+pub fn verify(proof: &Proof, server_seed: &str, command: &str, roll: &Roll) -> bool {
+    if commit(server_seed) != proof.commitment {
+        return false;
+    }
+
+    let seed = roll_seed(server_seed, &proof.client_seed, proof.nonce);
+    let expected = match command::parse(command) {
+        Ok(command) => command.roll(proof.rounding, &mut SeededRng::new(seed)).ok(),
+        Err(_) => None,
+    };
+    match expected {
+        Some(expected) => {
+            expected.equation == roll.equation &&
+                expected.value == roll.value &&
+                expected.dice.len() == roll.dice.len() &&
+                expected.dice.iter().zip(roll.dice.iter()).all(|(a, b)| a.value == b.value && a.is_dropped == b.is_dropped)
+        },
+        None => false,
+    }
+}
+
+#[test]
+fn it_can_commit_to_a_seed() {
+    let commitment = Commitment::new().unwrap();
+    assert_eq!(commitment.server_seed.len(), 64);
+    assert_eq!(commitment.commitment, commit(&commitment.server_seed));
+    assert_ne!(commitment.commitment, commitment.server_seed);
+}
+
+#[test]
+fn it_can_derive_roll_seeds() {
+    assert_eq!(roll_seed("server", "client", 0), roll_seed("server", "client", 0));
+    assert_ne!(roll_seed("server", "client", 0), roll_seed("server", "client", 1));
+    assert_ne!(roll_seed("server", "client", 0), roll_seed("server", "other", 0));
+}
+
+#[test]
+fn it_can_verify_rolls() {
+    let mut commitment = Commitment::new().unwrap();
+    let (nonce, seed) = commitment.next_seed("lucky").unwrap();
+    let roll = command::parse("4d6kh3+1d20").unwrap().roll(Rounding::default(), &mut SeededRng::new(seed)).unwrap();
+    let proof = Proof {
+        commitment_id: commitment.id.clone(),
+        commitment: commitment.commitment.clone(),
+        client_seed: "lucky".to_string(),
+        nonce,
+        rounding: Rounding::default(),
+    };
+
+    let server_seed = commitment.reveal().to_string();
+    assert!(commitment.next_seed("lucky").is_none());
+    assert!(verify(&proof, &server_seed, "4d6kh3+1d20", &roll));
+
+    // A different seed or different dice do not verify
+    assert!(!verify(&proof, "not the seed", "4d6kh3+1d20", &roll));
+    let mut forged = roll.clone();
+    forged.dice[0].value = if forged.dice[0].value == 6 { 5 } else { 6 };
+    assert!(!verify(&proof, &server_seed, "4d6kh3+1d20", &forged));
+
+    // Neither can a different total or equation with the same dice
+    let mut forged = roll.clone();
+    forged.value += 1;
+    assert!(!verify(&proof, &server_seed, "4d6kh3+1d20", &forged));
+    let mut forged = roll.clone();
+    forged.equation = "4d6kh3+1d20+10".to_string();
+    assert!(!verify(&proof, &server_seed, "4d6kh3+1d20", &forged));
+}
+
+#[test]
+fn it_can_verify_rolls_with_the_proofs_rounding() {
+    let mut commitment = Commitment::new().unwrap();
+    let (nonce, seed) = commitment.next_seed("lucky").unwrap();
+    let mut proof = Proof {
+        commitment_id: commitment.id.clone(),
+        commitment: commitment.commitment.clone(),
+        client_seed: "lucky".to_string(),
+        nonce,
+        rounding: Rounding::Ceil,
+    };
+
+    // Half of 1 is 1 rounded up, but 0 rounded down
+    let server_seed = commitment.reveal().to_string();
+    let roll = command::parse("1d1/2").unwrap().roll(Rounding::Ceil, &mut SeededRng::new(seed)).unwrap();
+    assert_eq!(roll.value, 1);
+    assert!(verify(&proof, &server_seed, "1d1/2", &roll));
+    proof.rounding = Rounding::Floor;
+    assert!(!verify(&proof, &server_seed, "1d1/2", &roll));
+}
@@ -0,0 +1,72 @@
+//! Roll dice with the Tabletop Macro Language
+//!
+//! The engine behind the Roll API, usable without the HTTP server:
+//!
+//! ```ignore
+//! extern crate roll_api;
+//!
+//! let roll = roll_api::roll("4d6kh3").unwrap();
+//! println!("{}", roll.value);
+//! ```
+
+#[macro_use] extern crate serde_derive;
+
+extern crate chrono;
+extern crate hex;
+extern crate hmac;
+extern crate rand;
+extern crate serde_json;
+extern crate sha2;
+extern crate ttml;
+extern crate uuid;
+
+pub mod character;
+pub mod command;
+pub mod die;
+pub mod distribution;
+pub mod error;
+pub mod expression;
+pub mod fair;
+pub mod macros;
+pub mod receipt;
+pub mod rng;
+pub mod roll;
+pub mod room;
+pub mod seal;
+pub mod simulation;
+pub mod storage;
+pub mod symbolic;
+
+use error::RollError;
+use expression::Rounding;
+use rng::{DiceRng, SystemRng};
+#[cfg(test)]
+use rng::SeededRng;
+use roll::Roll;
+
+/// Roll a command with the operating system's random numbers, rounding divisions down
+pub fn roll(command: &str) -> Result<Roll, RollError> {
+    let mut rng = SystemRng::new().map_err(|e| RollError::Random { offset: 0, message: e.to_string() })?;
+    roll_with(command, Rounding::default(), &mut rng)
+}
+
+/// Roll a command with a given rounding and source of random numbers
+pub fn roll_with<R: DiceRng + ?Sized>(command: &str, rounding: Rounding, rng: &mut R) -> Result<Roll, RollError> {
+    command::parse(command)?.roll(rounding, rng)
+}
+
+#[test]
+fn it_can_roll_commands() {
+    let stats = roll("4d6kh3").unwrap();
+    assert!(stats.value >= 3 && stats.value <= 18);
+
+    let first = roll_with("2d20+5", Rounding::Floor, &mut SeededRng::new(7)).unwrap();
+    let second = roll_with("2d20+5", Rounding::Floor, &mut SeededRng::new(7)).unwrap();
+    assert_eq!(first.value, second.value);
+
+    match roll_with("1d20/0", Rounding::Floor, &mut SeededRng::new(7)) {
+        Err(RollError::Evaluate { .. }) => {}, // do nothing
+        _ => panic!("Dividing by zero should not evaluate"),
+    }
+    assert_eq!(roll("(1d20").unwrap_err().offset(), 5);
+}
@@ -0,0 +1,205 @@
+use chrono::DateTime;
+use chrono::prelude::Utc;
+use command;
+use error::RollError;
+use receipt;
+use serde_json::Value;
+use std::collections::HashMap;
+
+/// A saved command with `{variable}` placeholders (e.g. "1d20+{str}+{prof}")
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Macro {
+    /// Unique name of the macro (e.g. "attack")
+    pub name: String,
+
+    pub command: String,
+
+    /// Secret needed to change or delete the macro, only given out when it is created
+    #[serde(skip)]
+    pub owner_token: String,
+
+    /// Names of the placeholders in the command, in the order they first appear
+    pub variables: Vec<String>,
+
+    /// Timestamp of when the macro was last saved
+    pub timestamp: DateTime<Utc>,
+}
+
+impl Macro {
+    pub fn new(name: &str, command: &str) -> Result<Macro, RollError> {
+        Ok(Macro {
+            name: name.to_string(),
+            command: command.to_string(),
+            owner_token: receipt::generate_key().map_err(|e| RollError::Random { offset: 0, message: e.to_string() })?,
+            variables: variables(command)?,
+            timestamp: Utc::now(),
+        })
+    }
+
+    /// If a token is the one given out when the macro was created, compared in constant time
+    pub fn is_owner(&self, token: &str) -> bool {
+        receipt::constant_time_eq(self.owner_token.as_bytes(), token.as_bytes())
+    }
+
+    /// Substitute values for the placeholders, giving the equation to roll
+    pub fn resolve(&self, values: &HashMap<String, Value>) -> Result<String, RollError> {
+        substitute(&self.command, values)
+    }
+}
+
+/// Split a command into literal text and placeholder names with their offsets
+///
+/// Comments are literal text, so braces inside `[...]` are left alone.
+fn placeholders(command: &str) -> Result<Vec<(usize, Option<String>, String)>, RollError> {
+    let mut parts = vec![];
+    let mut start = 0;
+    let mut offset = 0;
+    while let Some(c) = command[offset..].chars().next() {
+        match c {
+            '[' => {
+                offset = match command[offset..].find(']') {
+                    Some(close) => offset + close + 1,
+                    None => command.len(),
+                };
+            },
+            '{' => {
+                let close = command[offset..].find('}').ok_or_else(|| RollError::Parse {
+                    offset,
+                    message: "Expected \"}\"".to_string(),
+                })?;
+                let name = &command[offset + 1..offset + close];
+                let is_valid = !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+                if !is_valid {
+                    return Err(RollError::Parse {
+                        offset,
+                        message: format!("\"{{{}}}\" is not a valid variable, use letters, digits and _", name),
+                    });
+                }
+                parts.push((start, None, command[start..offset].to_string()));
+                parts.push((offset, Some(name.to_string()), String::new()));
+                offset += close + 1;
+                start = offset;
+            },
+            '}' => return Err(RollError::Parse {
+                offset,
+                message: "Unexpected \"}\"".to_string(),
+            }),
+            _ => offset += c.len_utf8(),
+        }
+    }
+    parts.push((start, None, command[start..].to_string()));
+    Ok(parts)
+}
+
+/// Names of the placeholders in a command, in the order they first appear
+pub fn variables(command: &str) -> Result<Vec<String>, RollError> {
+    let mut names: Vec<String> = vec![];
+    for (_, name, _) in placeholders(command)? {
+        if let Some(name) = name {
+            if !names.contains(&name) {
+                names.push(name);
+            }
+        }
+    }
+    Ok(names)
+}
+
+/// Write a value into the equation
+///
+/// Whole numbers are written as they are so they can be used as a number of dice or sides (e.g.
+/// "{level}d6"), anything else is wrapped in parentheses (e.g. "2*{weapon}" with "1d8+2"). Commands
+/// must parse on their own and can't have comments, so a value can't close the parentheses or change
+/// the rest of the macro.
+fn format_value(name: &str, value: &Value, offset: usize) -> Result<String, RollError> {
+    let text = match value {
+        &Value::Number(ref number) => number.to_string(),
+        &Value::String(ref text) => text.trim().to_string(),
+        _ => return Err(RollError::UnsupportedArgument {
+            offset,
+            message: format!("The value of \"{}\" must be a number or a command", name),
+        }),
+    };
+    if !text.is_empty() && text.chars().all(|c| c.is_ascii_digit()) {
+        return Ok(text);
+    }
+    let is_valid = !text.contains(|c| c == '[' || c == ']') && command::parse(&text).is_ok();
+    if !is_valid {
+        return Err(RollError::UnsupportedArgument {
+            offset,
+            message: format!("The value of \"{}\" must be a number or a command without comments", name),
+        });
+    }
+    Ok(format!("({})", text))
+}
+
+/// Substitute values for every `{variable}` in a command
+///
+/// Errors point at the placeholder in the command, e.g. when a value is missing.
+pub fn substitute(command: &str, values: &HashMap<String, Value>) -> Result<String, RollError> {
+    let mut equation = String::new();
+    for (offset, name, text) in placeholders(command)? {
+        match name {
+            Some(name) => match values.get(&name) {
+                Some(value) => equation.push_str(&format_value(&name, value, offset)?),
+                None => return Err(RollError::Parse {
+                    offset,
+                    message: format!("Missing a value for \"{}\"", name),
+                }),
+            },
+            None => equation.push_str(&text),
+        }
+    }
+    Ok(equation)
+}
+
+#[test]
+fn it_can_substitute_variables() {
+    let attack = Macro::new("attack", "1d20+{str}+{prof} [{str} strength]").unwrap();
+    assert_eq!(attack.variables, vec!["str", "prof"]);
+
+    let mut values = HashMap::new();
+    values.insert("str".to_string(), Value::from(3));
+    values.insert("prof".to_string(), Value::from(2));
+    assert_eq!(attack.resolve(&values).unwrap(), "1d20+3+2 [{str} strength]");
+
+    // Negative numbers and commands keep their precedence
+    values.insert("str".to_string(), Value::from(-1));
+    values.insert("prof".to_string(), Value::from("1d4+1"));
+    assert_eq!(attack.resolve(&values).unwrap(), "1d20+(-1)+(1d4+1) [{str} strength]");
+
+    let fireball = Macro::new("fireball", "{level}d6").unwrap();
+    assert!(fireball.is_owner(&fireball.owner_token));
+    assert!(!fireball.is_owner(&attack.owner_token));
+    assert!(!fireball.is_owner(""));
+    assert!(!::serde_json::to_string(&fireball).unwrap().contains(&fireball.owner_token));
+    values.insert("level".to_string(), Value::from("8"));
+    assert_eq!(fireball.resolve(&values).unwrap(), "8d6");
+    assert!(::roll(&fireball.resolve(&values).unwrap()).is_ok());
+}
+
+#[test]
+fn it_can_report_macro_errors() {
+    assert_eq!(variables("1d20+{str").unwrap_err().offset(), 5);
+    assert_eq!(variables("1d20+str}").unwrap_err().offset(), 8);
+    assert_eq!(variables("1d20+{}").unwrap_err().offset(), 5);
+    assert_eq!(variables("1d20+{s r}").unwrap_err().offset(), 5);
+    assert_eq!(variables("1d20 [{not a variable}]").unwrap().len(), 0);
+
+    let mut values = HashMap::new();
+    values.insert("str".to_string(), Value::from(3));
+    let error = substitute("1d20+{str}+{prof}", &values).unwrap_err();
+    assert_eq!(error.offset(), 11);
+    assert_eq!(error.message(), "Missing a value for \"prof\"");
+
+    values.insert("prof".to_string(), Value::Bool(true));
+    match substitute("1d20+{str}+{prof}", &values) {
+        Err(RollError::UnsupportedArgument { offset, .. }) => assert_eq!(offset, 11),
+        other => panic!("Expected an unsupported argument, got {:?}", other),
+    }
+
+    // Values can't break out of their parentheses or add comments
+    for value in ["1d20)*1000d1000", "1d20)*(1000d1000", "1d4 [ignore the rest", "not dice"].iter() {
+        values.insert("prof".to_string(), Value::from(*value));
+        assert_eq!(substitute("1d20+{str}+{prof}", &values).unwrap_err().offset(), 11, "{}", value);
+    }
+}
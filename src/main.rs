@@ -8,19 +8,85 @@ extern crate chrono;
 extern crate rand;
 extern crate rocket;
 extern crate rocket_contrib;
-extern crate ttml;
-extern crate uuid;
+extern crate roll_api;
+
+use roll_api::storage::{FileStore, MemoryStore};
+use roll_api::symbolic::DiceRegistry;
+use std::collections::HashMap;
+use std::sync::Mutex;
 
-pub mod die;
 pub mod config;
 pub mod cors;
-pub mod roll;
+pub mod events;
 pub mod v1;
 
+/// Record rolls in the file set by `roll_history` in Rocket.toml, or in memory when there is none
+///
+/// In memory, only the most recent `max_history` rolls are kept.
+fn roll_store(config: &rocket::Config) -> v1::Rolls {
+    match config.get_str("roll_history") {
+        Ok(path) => Box::new(FileStore::open(path).expect("Could not open the roll history")),
+        Err(_) => Box::new(MemoryStore::new(config.get_int("max_history").unwrap_or(10000) as usize)),
+    }
+}
+
 fn rocket() -> rocket::Rocket {
-    rocket::ignite()
+    let rocket = rocket::ignite();
+    let rolls = roll_store(rocket.config());
+
+    // Room events are served on their own port so listeners don't hold the API's workers
+    let rooms = v1::Rooms::new(Mutex::new(HashMap::new()));
+    events::serve(rooms.clone(), events::Settings::new(rocket.config())).expect("Could not start the room event server");
+
+    rocket
         .mount("/", routes![index])
-        .mount("/v1", routes![v1::roll])
+        .mount("/v1", routes![
+            v1::roll,
+            v1::roll_with_options,
+            v1::roll_with_body,
+            v1::roll_batch,
+            v1::get_roll,
+            v1::list_rolls,
+            v1::list_rolls_with_options,
+            v1::verify,
+            v1::create_commitment,
+            v1::roll_with_commitment,
+            v1::reveal_commitment,
+            v1::distribution,
+            v1::distribution_with_options,
+            v1::simulate,
+            v1::simulate_with_options,
+            v1::create_sealed,
+            v1::get_sealed,
+            v1::reveal_sealed_roll,
+            v1::create_character,
+            v1::get_character,
+            v1::update_character,
+            v1::delete_character,
+            v1::list_macros,
+            v1::create_macro,
+            v1::get_macro,
+            v1::update_macro,
+            v1::delete_macro,
+            v1::roll_macro,
+            v1::create_room,
+            v1::get_room,
+            v1::join_room,
+            v1::roll_in_room,
+            v1::list_room_rolls,
+            v1::list_room_rolls_with_options,
+            v1::list_dice,
+            v1::create_die,
+            v1::get_die,
+            v1::update_die,
+            v1::delete_die
+        ])
+        .manage(v1::Characters::new(HashMap::new()))
+        .manage(v1::Commitments::new(HashMap::new()))
+        .manage(v1::Dice::new(DiceRegistry::genesys()))
+        .manage(v1::Macros::new(HashMap::new()))
+        .manage(rooms)
+        .manage(rolls)
         .attach(config::ConfigMiddleware)
         .attach(cors::CORS)
 }
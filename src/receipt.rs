@@ -0,0 +1,204 @@
+#[cfg(test)]
+use expression::Rounding;
+use hex;
+use hmac::{Hmac, Mac};
+use rand::{OsRng, Rng};
+#[cfg(test)]
+use rng::SeededRng;
+use roll::Roll;
+use serde_json::{self, Value};
+use sha2::Sha256;
+use std::io;
+
+/// The parts of a roll covered by its signature: everything but the signature itself, down to the
+/// nested `rolls`, the `expression` and every flag on every die
+///
+/// Rolls are serialized the same way every time, maps are sorted by key, so the same roll always gives
+/// the same canonical JSON, even after a round trip through a client.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Receipt(pub Value);
+
+impl Receipt {
+    pub fn new(roll: &Roll) -> Receipt {
+        let mut value = serde_json::to_value(roll).unwrap();
+        if let Value::Object(ref mut fields) = value {
+            fields.remove("signature");
+        }
+        Receipt(value)
+    }
+
+    /// The canonical JSON that is signed
+    pub fn canonical(&self) -> String {
+        serde_json::to_string(&self.0).unwrap()
+    }
+}
+
+/// Generate a random signing key, hex encoded
+pub fn generate_key() -> io::Result<String> {
+    let mut bytes = [0u8; 32];
+    OsRng::new()?.fill_bytes(&mut bytes);
+    Ok(hex::encode(&bytes[..]))
+}
+
+/// Compare two secrets without returning early, so the time taken doesn't give away how much of a
+/// guess was right
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b.iter()).fold(0, |difference, (x, y)| difference | (x ^ y)) == 0
+}
+
+fn mac(roll: &Roll, key: &[u8]) -> Hmac<Sha256> {
+    // HMAC accepts keys of any length
+    let mut mac = Hmac::<Sha256>::new_varkey(key).unwrap();
+    mac.input(Receipt::new(roll).canonical().as_bytes());
+    mac
+}
+
+/// HMAC-SHA256 of the roll's canonical JSON, hex encoded
+pub fn sign(roll: &Roll, key: &[u8]) -> String {
+    hex::encode(mac(roll, key).result().code().as_slice())
+}
+
+/// Check the signature of a roll, e.g. one pasted into a tournament chat
+///
+/// Fails if the signature is missing, or if anything in the roll has changed since it was signed, e.g.
+/// its label, a die being dropped, a nested roll or the seed it was rolled with.
+pub fn verify(roll: &Roll, key: &[u8]) -> bool {
+    let signature = match roll.signature {
+        Some(ref signature) => signature,
+        None => return false,
+    };
+    match hex::decode(signature) {
+        Ok(code) => mac(roll, key).verify(&code).is_ok(),
+        Err(_) => false,
+    }
+}
+
+#[test]
+fn it_can_sign_rolls() {
+    let mut roll = ::roll_with("4d6kh3+2", Rounding::Floor, &mut SeededRng::new(1)).unwrap();
+    assert!(!verify(&roll, b"secret"));
+
+    roll.signature = Some(sign(&roll, b"secret"));
+    assert!(verify(&roll, b"secret"));
+    assert!(!verify(&roll, b"another secret"));
+
+    // The signature survives a round trip through JSON
+    let json = serde_json::to_string(&roll).unwrap();
+    let parsed: Roll = serde_json::from_str(&json).unwrap();
+    assert!(verify(&parsed, b"secret"));
+}
+
+#[test]
+fn it_can_detect_forged_rolls() {
+    let mut roll = ::roll_with("1d20+5", Rounding::Floor, &mut SeededRng::new(1)).unwrap();
+    roll.signature = Some(sign(&roll, b"secret"));
+
+    let mut forged = roll.clone();
+    forged.value = 25;
+    assert!(!verify(&forged, b"secret"));
+
+    let mut forged = roll.clone();
+    forged.dice[0].value = 20;
+    assert!(!verify(&forged, b"secret"));
+
+    let mut forged = roll.clone();
+    forged.equation = "1d20+10".to_string();
+    assert!(!verify(&forged, b"secret"));
+
+    let mut forged = roll.clone();
+    forged.signature = Some("not hex".to_string());
+    assert!(!verify(&forged, b"secret"));
+}
+
+#[test]
+fn it_can_detect_relabelled_and_reseeded_rolls() {
+    let mut roll = ::roll_with("4d6kh3 [Attack on goblin]", Rounding::Floor, &mut SeededRng::new(1)).unwrap();
+    roll.label = Some("Attack".to_string());
+    roll.signature = Some(sign(&roll, b"secret"));
+    assert!(verify(&roll, b"secret"));
+
+    let mut forged = roll.clone();
+    forged.comment = "Death save".to_string();
+    assert!(!verify(&forged, b"secret"));
+
+    let mut forged = roll.clone();
+    forged.label = Some("Death save".to_string());
+    assert!(!verify(&forged, b"secret"));
+
+    let mut forged = roll.clone();
+    let dropped = forged.dice.iter().position(|die| die.is_dropped).unwrap();
+    forged.dice[dropped].is_dropped = false;
+    assert!(!verify(&forged, b"secret"));
+
+    // A roll the client chose the seed for can't pass for a random one
+    let mut seeded = roll.clone();
+    seeded.seed = Some(1);
+    seeded.signature = Some(sign(&seeded, b"secret"));
+    let mut forged = seeded.clone();
+    forged.seed = None;
+    assert!(!verify(&forged, b"secret"));
+}
+
+#[test]
+fn it_can_detect_forged_pools_and_symbols() {
+    let mut roll = ::roll_with("6d10s>=8", Rounding::Floor, &mut SeededRng::new(1)).unwrap();
+    roll.signature = Some(sign(&roll, b"secret"));
+    let mut forged = roll.clone();
+    if let Some(ref mut pool) = forged.pool {
+        pool.successes += 1;
+    }
+    assert!(!verify(&forged, b"secret"));
+
+    let mut roll = ::symbolic::DiceRegistry::genesys().roll("2dAbility", &mut SeededRng::new(1)).unwrap();
+    roll.signature = Some(sign(&roll, b"secret"));
+    assert!(verify(&roll, b"secret"));
+
+    let mut forged = roll.clone();
+    forged.symbols.as_mut().unwrap().insert("triumph".to_string(), 1);
+    assert!(!verify(&forged, b"secret"));
+
+    let mut forged = roll.clone();
+    forged.dice[0].symbols.as_mut().unwrap().insert("triumph".to_string(), 1);
+    assert!(!verify(&forged, b"secret"));
+}
+
+#[test]
+fn it_can_compare_secrets() {
+    assert!(constant_time_eq(b"secret", b"secret"));
+    assert!(!constant_time_eq(b"secret", b"secreT"));
+    assert!(!constant_time_eq(b"secret", b"secret2"));
+    assert!(constant_time_eq(b"", b""));
+}
+
+#[test]
+fn it_can_detect_forged_nested_rolls_and_flags() {
+    let mut roll = ::roll_with("1d20adv+1d6", Rounding::Floor, &mut SeededRng::new(1)).unwrap();
+    roll.signature = Some(sign(&roll, b"secret"));
+
+    let mut forged = roll.clone();
+    forged.rolls[1].value += 1;
+    assert!(!verify(&forged, b"secret"));
+
+    let mut forged = roll.clone();
+    forged.advantage = None;
+    forged.rolls[0].advantage = None;
+    assert!(!verify(&forged, b"secret"));
+
+    let mut forged = roll.clone();
+    forged.expression = None;
+    assert!(!verify(&forged, b"secret"));
+
+    let mut forged = roll.clone();
+    forged.is_critical = !forged.is_critical;
+    assert!(!verify(&forged, b"secret"));
+
+    let mut forged = roll.clone();
+    forged.dice[0].is_critical = !forged.dice[0].is_critical;
+    assert!(!verify(&forged, b"secret"));
+
+    // The signature isn't part of what is signed
+    assert!(!Receipt::new(&roll).canonical().contains("signature\":\""));
+}
@@ -0,0 +1,122 @@
+use rand::distributions::{IndependentSample, Range};
+use rand::{ChaChaRng, OsRng, SeedableRng};
+use std::io;
+
+/// A source of random numbers used to roll dice
+pub trait DiceRng {
+    /// Generate a number between min and max, including both
+    fn between(&mut self, min: i16, max: i16) -> i16;
+}
+
+/// Deterministic random numbers, the same seed always rolls the same dice
+pub struct SeededRng {
+    rng: ChaChaRng,
+    seed: u64,
+}
+
+impl SeededRng {
+    pub fn new(seed: u64) -> SeededRng {
+        let key = [seed as u32, (seed >> 32) as u32];
+        SeededRng {
+            rng: ChaChaRng::from_seed(&key),
+            seed,
+        }
+    }
+
+    /// The seed the generator was created with
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+}
+
+impl DiceRng for SeededRng {
+    fn between(&mut self, min: i16, max: i16) -> i16 {
+        Range::new(min as i32, max as i32 + 1).ind_sample(&mut self.rng) as i16
+    }
+}
+
+/// Cryptographically secure random numbers from the operating system
+pub struct SystemRng {
+    rng: OsRng,
+}
+
+impl SystemRng {
+    pub fn new() -> io::Result<SystemRng> {
+        Ok(SystemRng {
+            rng: OsRng::new()?,
+        })
+    }
+}
+
+impl DiceRng for SystemRng {
+    fn between(&mut self, min: i16, max: i16) -> i16 {
+        Range::new(min as i32, max as i32 + 1).ind_sample(&mut self.rng) as i16
+    }
+}
+
+/// A fixed sequence of numbers, useful for testing
+///
+/// Each number is returned in turn, starting over once they run out, and clamped between the
+/// minimum and maximum that was asked for.
+pub struct FixedRng {
+    position: usize,
+    values: Vec<i16>,
+}
+
+impl FixedRng {
+    pub fn new(values: Vec<i16>) -> FixedRng {
+        FixedRng {
+            position: 0,
+            values,
+        }
+    }
+}
+
+impl DiceRng for FixedRng {
+    fn between(&mut self, min: i16, max: i16) -> i16 {
+        if self.values.is_empty() {
+            return min;
+        }
+        let value = self.values[self.position % self.values.len()];
+        self.position += 1;
+        if value < min {
+            min
+        } else if value > max {
+            max
+        } else {
+            value
+        }
+    }
+}
+
+#[test]
+fn it_can_repeat_seeded_rolls() {
+    let mut first = SeededRng::new(1337);
+    let mut second = SeededRng::new(1337);
+    let a: Vec<i16> = (0..100).map(|_| first.between(1, 20)).collect();
+    let b: Vec<i16> = (0..100).map(|_| second.between(1, 20)).collect();
+    assert_eq!(a, b);
+    assert!(a.iter().all(|v| *v >= 1 && *v <= 20));
+
+    let mut other = SeededRng::new(1338);
+    let c: Vec<i16> = (0..100).map(|_| other.between(1, 20)).collect();
+    assert_ne!(a, c);
+}
+
+#[test]
+fn it_can_roll_system_numbers() {
+    let mut rng = SystemRng::new().unwrap();
+    for _ in 0..100 {
+        let value = rng.between(-1, 1);
+        assert!(value >= -1 && value <= 1);
+    }
+}
+
+#[test]
+fn it_can_roll_fixed_numbers() {
+    let mut rng = FixedRng::new(vec![3, 25, -4]);
+    assert_eq!(rng.between(1, 20), 3);
+    assert_eq!(rng.between(1, 20), 20);
+    assert_eq!(rng.between(1, 20), 1);
+    assert_eq!(rng.between(1, 20), 3);
+}
@@ -2,14 +2,118 @@ use chrono::DateTime;
 use chrono::prelude::Utc;
 use die::Die;
 use die::DieType;
+use error::RollError;
+use expression::{Expression, Rounding};
+use rng::DiceRng;
+#[cfg(test)]
+use rng::{FixedRng, SeededRng};
+use std::collections::BTreeMap;
 use uuid::Uuid;
 use ttml::arg::ComparisonArg;
 
+/// The most bonus dice a single die can add by exploding
+pub const MAX_EXPLOSIONS: u16 = 100;
+
+/// The most dice a single group can roll
+pub const MAX_DICE: i16 = 1000;
+
+/// The most dice a single group can reroll with rr
+pub const MAX_REROLLS: u16 = 10000;
+
+/// How an exploding die adds to the roll
+#[derive(Copy, Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub enum Explosion {
+    /// Each explosion adds a bonus die (e.g. 1d6!)
+    Standard,
+
+    /// Each explosion is added to the value of the die that exploded (e.g. 1d6!!)
+    Compound,
+
+    /// Each explosion adds a bonus die with 1 subtracted from it (e.g. 1d6!p)
+    Penetrate,
+}
+
+/// Which die is taken when a die is rolled with advantage or disadvantage
+#[derive(Copy, Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AdvantageMode {
+    /// Take the highest die (e.g. 1d20adv)
+    Advantage,
+
+    /// Take the lowest die (e.g. 1d20dis)
+    Disadvantage,
+}
+
+/// The die taken and the dice discarded when rolling with advantage or disadvantage
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Advantage {
+    pub mode: AdvantageMode,
+
+    /// Die whose value was used
+    pub taken: Die,
+
+    /// Dice that were rolled but not used, not counting dice that were rerolled
+    pub discarded: Vec<Die>,
+}
+
+impl Advantage {
+    /// Find the taken and discarded dice once the others have been dropped
+    fn new(mode: AdvantageMode, dice: &[Die]) -> Option<Advantage> {
+        let taken = dice.iter().find(|d| !d.is_dropped)?.clone();
+        let discarded = dice.iter().filter(|d| d.is_dropped && !d.is_rerolled).cloned().collect();
+        Some(Advantage { mode, taken, discarded })
+    }
+}
+
+/// Name of a result on the Fate ladder, from Terrible (-2) to Legendary (+8)
+pub fn fate_ladder(value: i32) -> Option<&'static str> {
+    match value {
+        8 => Some("Legendary"),
+        7 => Some("Epic"),
+        6 => Some("Fantastic"),
+        5 => Some("Superb"),
+        4 => Some("Great"),
+        3 => Some("Good"),
+        2 => Some("Fair"),
+        1 => Some("Average"),
+        0 => Some("Mediocre"),
+        -1 => Some("Poor"),
+        -2 => Some("Terrible"),
+        _ => None,
+    }
+}
+
+/// Check a rolled value against a comparison
+pub fn compare(op: &ComparisonArg, value: i16, threshold: i16) -> bool {
+    match op {
+        &ComparisonArg::GreaterThan => value > threshold,
+        &ComparisonArg::GreaterThanOrEqual => value >= threshold,
+        &ComparisonArg::LessThan => value < threshold,
+        &ComparisonArg::LessThanOrEqual => value <= threshold,
+        &ComparisonArg::EqualTo => value == threshold,
+    }
+}
+
 // Rolls all the arguments into a single struct
+#[derive(Clone)]
 pub struct RollFlags {
+    pub advantage: Option<AdvantageMode>,
     pub comment: String,
+    pub critical: i16,
+    pub critical_op: Option<ComparisonArg>,
+    pub dh: i16,
     pub die: DieType,
+    pub dl: i16,
+    pub double: i16,
+    pub double_op: Option<ComparisonArg>,
     pub equation: String,
+    pub explode: i16,
+    pub explode_op: Option<ComparisonArg>,
+    pub explosion: Explosion,
+    pub failure: i16,
+    pub failure_op: Option<ComparisonArg>,
+    pub fumble: i16,
+    pub fumble_op: Option<ComparisonArg>,
     pub gt: u16,
     pub gte: u16,
     pub kh: i16,
@@ -25,14 +129,30 @@ pub struct RollFlags {
     pub rr_op: Option<ComparisonArg>,
     pub ro_op: Option<ComparisonArg>,
     pub sides: Option<Vec<i16>>,
+    pub success: i16,
+    pub success_op: Option<ComparisonArg>,
 }
 
 impl RollFlags {
     pub fn new() -> RollFlags {
         RollFlags {
+            advantage: None,
             comment: "".to_string(),
+            critical: 0,
+            critical_op: None,
+            dh: 0,
             die: DieType::Other,
+            dl: 0,
+            double: 0,
+            double_op: None,
             equation: "".to_string(),
+            explode: 0,
+            explode_op: None,
+            explosion: Explosion::Standard,
+            failure: 0,
+            failure_op: None,
+            fumble: 0,
+            fumble_op: None,
             gt: 0,
             gte: 0,
             kh: 0,
@@ -48,15 +168,43 @@ impl RollFlags {
             rr_op: None,
             ro_op: None,
             sides: None,
+            success: 0,
+            success_op: None,
         }
     }
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+/// The outcome of a dice pool, where the result is the number of successes
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Pool {
+    /// Dice that met the failure threshold, each one cancels a success
+    pub failures: i32,
+
+    /// If more than half of the dice failed and there were no successes (e.g. Shadowrun)
+    pub is_critical_glitch: bool,
+
+    /// If there were no successes and at least one failure (e.g. World of Darkness)
+    pub is_botch: bool,
+
+    /// If more than half of the dice failed (e.g. Shadowrun)
+    pub is_glitch: bool,
+
+    /// Dice that met the success threshold, counting dice worth two successes twice
+    pub successes: i32,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Roll {
+    /// The die taken and the dice discarded when rolled with advantage or disadvantage
+    pub advantage: Option<Advantage>,
+
     /// Comment associated with the roll
     pub comment: String,
 
+    /// Number of kept dice that are critical hits
+    #[serde(default)]
+    pub criticals: u32,
+
     /// Unique identifier for the roll
     pub id: String,
 
@@ -66,12 +214,49 @@ pub struct Roll {
     /// Calculated equation of the roll
     pub equation: String,
 
+    /// Number of kept dice that are fumbles
+    #[serde(default)]
+    pub fumbles: u32,
+
+    /// How the final value was computed from the dice groups and constants
+    pub expression: Option<Expression>,
+
+    /// If any kept die is a critical hit
+    #[serde(default)]
+    pub is_critical: bool,
+
+    /// If any kept die is a fumble
+    #[serde(default)]
+    pub is_fumble: bool,
+
+    /// Name given to the roll by the client (e.g. "Stealth check")
+    pub label: Option<String>,
+
+    /// Name of the result on the Fate ladder when Fate dice were rolled (e.g. "Good")
+    pub ladder: Option<String>,
+
     /// Modifiers to apply to the combined value
     pub modifiers: Vec<i16>,
 
+    /// Successes and failures when the roll is a dice pool
+    pub pool: Option<Pool>,
+
     /// The combined value of the die before modifiers
     pub raw_value: i32,
 
+    /// The individual dice groups that compose this roll (e.g. 1d20 and 2d6 in 1d20+2d6)
+    pub rolls: Vec<Roll>,
+
+    /// Seed the client chose to roll with, so the dice were reproducible rather than random
+    #[serde(default)]
+    pub seed: Option<u64>,
+
+    /// Signature over the roll's receipt, proving the server rolled it
+    pub signature: Option<String>,
+
+    /// What symbolic dice show once opposing symbols cancel out (e.g. { "advantage": 1 }), instead of a value
+    pub symbols: Option<BTreeMap<String, i32>>,
+
     /// Timestamp
     pub timestamp: DateTime<Utc>,
 
@@ -80,7 +265,10 @@ pub struct Roll {
 }
 
 impl Roll {
-    pub fn new(flags: RollFlags) -> Roll {
+    /// Roll a group of dice and apply its flags
+    ///
+    /// Fails if rerolling doesn't stop within `MAX_REROLLS` dice.
+    pub fn new<R: DiceRng + ?Sized>(flags: RollFlags, rng: &mut R) -> Result<Roll, RollError> {
         let mut dice = vec![];
         for _ in 0..flags.n {
             let mut die = Die::new(flags.die);
@@ -98,55 +286,126 @@ impl Roll {
 
         // Roll each dice
         for die in &mut dice {
-            die.roll();
+            die.roll(rng);
         }
 
         let mut roll = Roll {
+            advantage: None,
             comment: flags.comment,
+            criticals: 0,
             dice,
             equation: flags.equation,
+            expression: None,
+            fumbles: 0,
+            is_critical: false,
+            is_fumble: false,
+            label: None,
+            ladder: None,
             timestamp: Utc::now(),
             id: Uuid::new_v4().to_string(),
             modifiers: Vec::new(),
+            pool: None,
             raw_value: 0,
+            rolls: Vec::new(),
+            seed: None,
+            signature: None,
+            symbols: None,
             value: 0,
         };
 
         // If we have reroll flags, execute it
         match flags.rr_op {
             Some(op) => {
-                roll.reroll_dice_forever(&op, flags.rr);
+                roll.reroll_dice_forever(&op, flags.rr, rng)?;
             },
             None => {} // do nothing
         };
 
         match flags.ro_op {
             Some(op) => {
-                roll.reroll_dice_once(&op, flags.ro);
+                roll.reroll_dice_once(&op, flags.ro, rng);
             },
             None => {} // do nothing
         };
 
-        // Keep or drop dice that fit certain criteria
+        // Mark critical hits and fumbles, on the highest and lowest faces unless the command says otherwise
+        // Bonus dice from explosions are rolled afterwards, so they are never critical
+        let critical = match flags.critical_op {
+            Some(ref op) => Some((op, flags.critical)),
+            None => None,
+        };
+        let fumble = match flags.fumble_op {
+            Some(ref op) => Some((op, flags.fumble)),
+            None => None,
+        };
+        roll.mark_criticals(critical, fumble);
+
+        // Explode dice, adding bonus dice to the roll
+        match flags.explode_op {
+            Some(op) => {
+                roll.explode_dice(flags.explosion, &op, flags.explode, rng);
+            },
+            None => {} // do nothing
+        };
+
+        // Keep or drop dice that fit certain criteria, each one working on the dice the last left
         if flags.gt != 0 {
             roll.keep_greater_than(flags.gt);
-        } else if flags.gte != 0 {
+        }
+        if flags.gte != 0 {
             roll.keep_greater_than_or_equal_to(flags.gte);
-        } else if flags.lt != 0 {
+        }
+        if flags.lt != 0 {
             roll.keep_less_than(flags.lt);
-        } else if flags.lte != 0 {
+        }
+        if flags.lte != 0 {
             roll.keep_less_than_or_equal_to(flags.lte);
-        } else if flags.kh != 0 {
+        }
+        if flags.kh != 0 {
             roll.keep_high(flags.kh as u16);
-        } else if flags.kl != 0 {
+        }
+        if flags.kl != 0 {
             roll.keep_low(flags.kl as u16);
         }
+        if flags.dh != 0 {
+            roll.drop_high(flags.dh as u16);
+        }
+        if flags.dl != 0 {
+            roll.drop_low(flags.dl as u16);
+        }
+
+        // Label the die taken and the dice discarded by advantage or disadvantage
+        if let Some(mode) = flags.advantage {
+            roll.advantage = Advantage::new(mode, &roll.dice);
+        }
+
+        // Only dice that are kept make the roll critical
+        roll.criticals = roll.dice.iter().filter(|d| !d.is_dropped && d.is_critical).count() as u32;
+        roll.fumbles = roll.dice.iter().filter(|d| !d.is_dropped && d.is_fumble).count() as u32;
+        roll.is_critical = roll.criticals > 0;
+        roll.is_fumble = roll.fumbles > 0;
 
         // Once everything has been rerolled, dropped, etc, count the total
         let raw_value = roll.dice.iter().filter(|d| !d.is_dropped).fold(0, |sum, d| sum + d.value as i32);
         roll.raw_value = raw_value;
         roll.value = raw_value;
 
+        // Dice pools count successes instead of adding the dice together
+        match flags.success_op {
+            Some(ref op) => {
+                let failure = match flags.failure_op {
+                    Some(ref op) => Some((op, flags.failure)),
+                    None => None,
+                };
+                let double = match flags.double_op {
+                    Some(ref op) => Some((op, flags.double)),
+                    None => None,
+                };
+                roll.value = roll.count_successes((op, flags.success), failure, double);
+            },
+            None => {} // do nothing
+        };
+
         // Apply and add our modifiers
         if flags.modifiers.len() > 0 {
             for modifier in flags.modifiers.into_iter() {
@@ -155,9 +414,163 @@ impl Roll {
             }
         }
 
+        Ok(roll)
+    }
+
+    /// Combine several dice groups into a single roll, keeping each group intact
+    pub fn combine(mut rolls: Vec<Roll>) -> Roll {
+        if rolls.len() == 1 {
+            return rolls.remove(0);
+        }
+        Roll::group(rolls)
+    }
+
+    /// Build a roll from dice groups and the equation that combines them
+    ///
+    /// Returns `None` if the equation cannot be evaluated (e.g. division by zero).
+    pub fn from_expression(mut expression: Expression, mut rolls: Vec<Roll>, comment: String, rounding: Rounding) -> Option<Roll> {
+        let value = expression.evaluate(&rolls, rounding)?;
+
+        // A lone group of dice is returned as-is
+        let is_single = match expression {
+            Expression::Roll { .. } => rolls.len() == 1,
+            _ => false,
+        };
+        let mut roll = if is_single { rolls.remove(0) } else { Roll::group(rolls) };
+
+        if comment.len() > 0 {
+            roll.comment = if roll.comment.len() > 0 { roll.comment + " " + &comment } else { comment };
+        }
+        if !is_single {
+            roll.equation = expression.to_string();
+            roll.modifiers = expression.modifiers().into_iter().map(|m| m as i16).collect();
+            roll.value = value;
+        }
+        roll.expression = Some(expression);
+
+        // Results with Fate dice are read off the ladder
+        if roll.dice.iter().any(|d| d.die == DieType::Fate) {
+            roll.ladder = fate_ladder(roll.value).map(|name| name.to_string());
+        }
+
+        Some(roll)
+    }
+
+    /// Nest dice groups under a new roll whose value is the sum of the groups
+    fn group(rolls: Vec<Roll>) -> Roll {
+        let mut roll = Roll {
+            advantage: None,
+            comment: "".to_string(),
+            criticals: 0,
+            dice: Vec::new(),
+            equation: "".to_string(),
+            expression: None,
+            fumbles: 0,
+            is_critical: false,
+            is_fumble: false,
+            label: None,
+            ladder: None,
+            timestamp: Utc::now(),
+            id: Uuid::new_v4().to_string(),
+            modifiers: Vec::new(),
+            pool: None,
+            raw_value: 0,
+            rolls: Vec::new(),
+            seed: None,
+            signature: None,
+            symbols: None,
+            value: 0,
+        };
+
+        let mut comments: Vec<String> = vec![];
+        let mut equations: Vec<String> = vec![];
+        for r in rolls.iter() {
+            if r.comment.len() > 0 {
+                comments.push(r.comment.clone());
+            }
+            equations.push(r.equation.clone());
+            roll.dice.append(&mut r.dice.clone());
+            roll.modifiers.append(&mut r.modifiers.clone());
+            roll.criticals += r.criticals;
+            roll.fumbles += r.fumbles;
+            roll.raw_value += r.raw_value;
+            roll.value += r.value;
+        }
+        roll.comment = comments.join(" ");
+        roll.equation = equations.join(" + ");
+        roll.is_critical = roll.criticals > 0;
+        roll.is_fumble = roll.fumbles > 0;
+        roll.rolls = rolls;
+
         roll
     }
 
+    /// Count the dice that meet a success threshold, cancelling a success for every die that meets
+    /// the failure threshold. Returns the net number of successes.
+    pub fn count_successes(&mut self, success: (&ComparisonArg, i16), failure: Option<(&ComparisonArg, i16)>, double: Option<(&ComparisonArg, i16)>) -> i32 {
+        let mut pool = Pool {
+            failures: 0,
+            is_critical_glitch: false,
+            is_botch: false,
+            is_glitch: false,
+            successes: 0,
+        };
+
+        let mut count = 0;
+        for die in self.dice.iter_mut().filter(|d| !d.is_dropped) {
+            count += 1;
+            die.is_successful = compare(success.0, die.value, success.1);
+            die.is_failed = match failure {
+                Some((op, threshold)) => compare(op, die.value, threshold),
+                None => false,
+            };
+
+            if die.is_successful {
+                pool.successes += match double {
+                    Some((op, threshold)) if compare(op, die.value, threshold) => 2,
+                    _ => 1,
+                };
+            } else if die.is_failed {
+                pool.failures += 1;
+            }
+        }
+
+        pool.is_botch = pool.successes == 0 && pool.failures > 0;
+        pool.is_glitch = pool.failures * 2 > count;
+        pool.is_critical_glitch = pool.is_glitch && pool.successes == 0;
+
+        let net = pool.successes - pool.failures;
+        self.pool = Some(pool);
+        net
+    }
+
+    /// Mark the dice that are critical hits or fumbles, checking the natural roll of each die
+    ///
+    /// Without a comparison, dice are critical on their highest face and fumble on their lowest.
+    pub fn mark_criticals(&mut self, critical: Option<(&ComparisonArg, i16)>, fumble: Option<(&ComparisonArg, i16)>) {
+        for die in &mut self.dice {
+            // Symbolic dice have no highest or lowest face
+            if die.die == DieType::Symbolic {
+                continue;
+            }
+
+            // Compounded and penetrating dice keep their natural roll first
+            let natural = die.partials.first().cloned().unwrap_or(die.value);
+            let (lowest, highest) = match die.sides {
+                Some(ref sides) => (sides.iter().cloned().min().unwrap_or(die.min), sides.iter().cloned().max().unwrap_or(die.max)),
+                None => (die.min, die.max),
+            };
+            die.is_critical = match critical {
+                Some((op, threshold)) => compare(op, natural, threshold),
+                None => natural >= highest,
+            };
+            die.is_fumble = match fumble {
+                Some((op, threshold)) => compare(op, natural, threshold),
+                None => natural <= lowest,
+            };
+        }
+    }
+
     /// Keep the dice greater than a number
     pub fn keep_greater_than(&mut self, keep: u16) {
         for die in &mut self.dice {
@@ -207,7 +620,7 @@ impl Roll {
         // Sort the dice by value, drop everything below the keep value
         let mut count = 0;
         self.dice.sort_by(|a, b| b.value.cmp(&a.value));
-        for die in &mut self.dice {
+        for die in self.dice.iter_mut().filter(|d| !d.is_dropped) {
             if count >= keep {
                 die.drop();
             }
@@ -219,10 +632,10 @@ impl Roll {
 
     /// Keep the lowest rolled dice
     pub fn keep_low(&mut self, keep: u16) {
-        // Sort the dice by value, drop everything below the keep value
+        // Sort the dice by value, drop everything above the keep value
         let mut count = 0;
         self.dice.sort_by(|a, b| a.value.cmp(&b.value));
-        for die in &mut self.dice {
+        for die in self.dice.iter_mut().filter(|d| !d.is_dropped) {
             if count >= keep {
                 die.drop();
             }
@@ -232,8 +645,92 @@ impl Roll {
         self.dice.sort_by(|a, b| a.timestamp.cmp(&b.timestamp));
     }
 
+    /// Drop the highest rolled dice
+    pub fn drop_high(&mut self, drop: u16) {
+        // Sort the dice by value, drop everything up to the drop value
+        let mut count = 0;
+        self.dice.sort_by(|a, b| b.value.cmp(&a.value));
+        for die in self.dice.iter_mut().filter(|d| !d.is_dropped) {
+            if count < drop {
+                die.drop();
+            }
+            count += 1;
+        }
+        // sort by timestamp again before finishing the method
+        self.dice.sort_by(|a, b| a.timestamp.cmp(&b.timestamp));
+    }
+
+    /// Drop the lowest rolled dice
+    pub fn drop_low(&mut self, drop: u16) {
+        // Sort the dice by value, drop everything up to the drop value
+        let mut count = 0;
+        self.dice.sort_by(|a, b| a.value.cmp(&b.value));
+        for die in self.dice.iter_mut().filter(|d| !d.is_dropped) {
+            if count < drop {
+                die.drop();
+            }
+            count += 1;
+        }
+        // sort by timestamp again before finishing the method
+        self.dice.sort_by(|a, b| a.timestamp.cmp(&b.timestamp));
+    }
+
+    /// Explode dice that are above or below a certain threshold, adding to the total. Bonus dice
+    /// can explode too, up to `MAX_EXPLOSIONS` times per die.
+    ///
+    /// Compounded dice keep every roll on the one die, so keeping and dropping treats them as a
+    /// single die.
+    pub fn explode_dice<R: DiceRng + ?Sized>(&mut self, explosion: Explosion, op: &ComparisonArg, threshold: i16, rng: &mut R) {
+        let mut new_dice = Vec::new();
+        for die in &mut self.dice {
+            if die.is_dropped || !compare(op, die.value, threshold) {
+                continue;
+            }
+
+            if explosion == Explosion::Compound {
+                let mut explosions = 0;
+                loop {
+                    let natural = die.compound(rng);
+                    explosions += 1;
+                    if !compare(op, natural, threshold) || explosions >= MAX_EXPLOSIONS {
+                        break;
+                    }
+                }
+                continue;
+            }
+
+            let mut bonus = Die::like(die);
+            bonus.roll(rng);
+            die.exploded(&bonus);
+
+            let mut explosions = 1;
+            loop {
+                // Penetrating dice explode on the natural roll, but count for one less
+                let natural = bonus.value;
+                if explosion == Explosion::Penetrate {
+                    bonus.partials.push(natural);
+                    bonus.value = natural - 1;
+                }
+
+                if !compare(op, natural, threshold) || explosions >= MAX_EXPLOSIONS {
+                    break;
+                }
+
+                let mut next = Die::like(&bonus);
+                next.roll(rng);
+                bonus.exploded(&next);
+                new_dice.push(bonus);
+                bonus = next;
+                explosions += 1;
+            }
+            new_dice.push(bonus);
+        }
+
+        self.dice.append(&mut new_dice);
+    }
+
     /// Reroll dice one time that are above or below a certain threshold
-    pub fn reroll_dice_once(&mut self, op: &ComparisonArg, threshold: i16) {
+    pub fn reroll_dice_once<R: DiceRng + ?Sized>(&mut self, op: &ComparisonArg, threshold: i16, rng: &mut R) {
         let mut new_dice = Vec::new();
         for die in &mut self.dice {
             let comparison = match op {
@@ -245,8 +742,8 @@ impl Roll {
             };
 
             if comparison {
-                let mut d = Die::new(die.die);
-                d.roll();
+                let mut d = Die::like(die);
+                d.roll(rng);
                 &die.rerolled(&d);
                 &die.drop();
                 new_dice.push(d);
@@ -256,27 +753,292 @@ impl Roll {
         self.dice.append(&mut new_dice);
     }
 
-    /// Reroll dice forever that are above or below a certain threshold
-    pub fn reroll_dice_forever(&mut self, op: &ComparisonArg, threshold: i16) {
-        // Reroll any dice that need to be rerolled
-        self.reroll_dice_once(&op, threshold);
-
-        let mut has_more = false;
-        for die in self.dice.iter() {
-            let comparison = match op {
-                &ComparisonArg::GreaterThan => !die.is_rerolled && die.value > threshold,
-                &ComparisonArg::GreaterThanOrEqual => !die.is_rerolled && die.value >= threshold,
-                &ComparisonArg::LessThan => !die.is_rerolled && die.value < threshold,
-                &ComparisonArg::LessThanOrEqual => !die.is_rerolled && die.value <= threshold,
-                &ComparisonArg::EqualTo => !die.is_rerolled && die.value == threshold,
-            };
+    /// Reroll dice forever that are above or below a certain threshold, up to `MAX_REROLLS`
+    /// rerolled dice
+    ///
+    /// Each round only checks the dice added by the round before.
+    pub fn reroll_dice_forever<R: DiceRng + ?Sized>(&mut self, op: &ComparisonArg, threshold: i16, rng: &mut R) -> Result<(), RollError> {
+        let mut rerolls = 0;
+        let mut start = 0;
+        while start < self.dice.len() {
+            let end = self.dice.len();
+            let mut new_dice = Vec::new();
+            for die in &mut self.dice[start..end] {
+                if die.is_rerolled || !compare(op, die.value, threshold) {
+                    continue;
+                }
+                if rerolls >= MAX_REROLLS {
+                    return Err(RollError::LimitExceeded {
+                        offset: 0,
+                        message: format!("Can't reroll more than {} dice at once", MAX_REROLLS),
+                    });
+                }
 
-            if comparison {
-                has_more = true
+                let mut d = Die::like(die);
+                d.roll(rng);
+                die.rerolled(&d);
+                die.drop();
+                new_dice.push(d);
+                rerolls += 1;
             }
+
+            self.dice.append(&mut new_dice);
+            start = end;
         }
-        if has_more {
-            self.reroll_dice_forever(op, threshold);
-        }
+        Ok(())
     }
 }
+
+#[test]
+fn it_can_combine_rolls() {
+    let mut rng = SeededRng::new(1);
+    let mut d20 = RollFlags::new();
+    d20.n = 1;
+    d20.die = DieType::D20;
+    d20.max = 20;
+    d20.equation = "1d20".to_string();
+
+    let mut d6 = RollFlags::new();
+    d6.n = 2;
+    d6.die = DieType::D6;
+    d6.max = 6;
+    d6.equation = "2d6".to_string();
+    d6.modifiers = vec![3];
+
+    let first = Roll::new(d20, &mut rng).unwrap();
+    let second = Roll::new(d6, &mut rng).unwrap();
+    let total = first.value + second.value;
+
+    let roll = Roll::combine(vec![first, second]);
+    assert_eq!(roll.rolls.len(), 2);
+    assert_eq!(roll.dice.len(), 3);
+    assert_eq!(roll.equation, "1d20 + 2d6");
+    assert_eq!(roll.modifiers, vec![3]);
+    assert_eq!(roll.value, total);
+    assert_eq!(roll.rolls[1].dice.len(), 2);
+}
+
+#[test]
+fn it_can_explode_dice() {
+    let mut rng = SeededRng::new(1);
+    // A die that always rolls its maximum explodes until the cap is reached
+    let mut flags = RollFlags::new();
+    flags.n = 1;
+    flags.max = 6;
+    flags.sides = Some(vec![6]);
+    flags.explode = 6;
+    flags.explode_op = Some(ComparisonArg::GreaterThanOrEqual);
+
+    let roll = Roll::new(flags, &mut rng).unwrap();
+    assert_eq!(roll.dice.len(), 1 + MAX_EXPLOSIONS as usize);
+    assert_eq!(roll.value, 6 * (1 + MAX_EXPLOSIONS as i32));
+    assert!(roll.dice[0].is_exploded);
+    assert_eq!(roll.dice[0].child, Some(roll.dice[1].id.clone()));
+    assert!(!roll.dice[MAX_EXPLOSIONS as usize].is_exploded);
+
+    let mut flags = RollFlags::new();
+    flags.n = 2;
+    flags.max = 6;
+    flags.sides = Some(vec![1]);
+    flags.explode = 6;
+    flags.explode_op = Some(ComparisonArg::GreaterThanOrEqual);
+
+    let roll = Roll::new(flags, &mut rng).unwrap();
+    assert_eq!(roll.dice.len(), 2);
+    assert_eq!(roll.value, 2);
+}
+
+#[test]
+fn it_can_compound_dice() {
+    let mut rng = SeededRng::new(1);
+    let mut flags = RollFlags::new();
+    flags.n = 2;
+    flags.max = 6;
+    flags.sides = Some(vec![6]);
+    flags.explode = 6;
+    flags.explode_op = Some(ComparisonArg::GreaterThanOrEqual);
+    flags.explosion = Explosion::Compound;
+    flags.kh = 1;
+
+    let roll = Roll::new(flags, &mut rng).unwrap();
+    assert_eq!(roll.dice.len(), 2);
+    assert_eq!(roll.dice[0].partials.len(), 1 + MAX_EXPLOSIONS as usize);
+    assert_eq!(roll.dice[0].value, 6 * (1 + MAX_EXPLOSIONS as i16));
+    assert!(roll.dice[0].child.is_none());
+
+    // Keeping the highest die keeps one compounded die, not one partial roll
+    assert_eq!(roll.dice.iter().filter(|d| !d.is_dropped).count(), 1);
+    assert_eq!(roll.value, 6 * (1 + MAX_EXPLOSIONS as i32));
+}
+
+#[test]
+fn it_can_count_successes() {
+    let mut rng = SeededRng::new(1);
+    let mut roll = Roll::new(RollFlags::new(), &mut rng).unwrap();
+    for value in vec![10, 8, 5, 1, 1, 3] {
+        let mut die = Die::new(DieType::D10);
+        die.value = value;
+        roll.dice.push(die);
+    }
+
+    let net = roll.count_successes(
+        (&ComparisonArg::GreaterThanOrEqual, 8),
+        Some((&ComparisonArg::LessThanOrEqual, 1)),
+        Some((&ComparisonArg::EqualTo, 10)),
+    );
+    let pool = roll.pool.clone().unwrap();
+    assert_eq!(pool.successes, 3);
+    assert_eq!(pool.failures, 2);
+    assert_eq!(net, 1);
+    assert!(!pool.is_botch);
+    assert!(!pool.is_glitch);
+    assert!(roll.dice[0].is_successful);
+    assert!(roll.dice[3].is_failed);
+
+    // No successes and a failure is a botch, more than half failing is a glitch
+    let net = roll.count_successes((&ComparisonArg::GreaterThan, 10), Some((&ComparisonArg::LessThanOrEqual, 3)), None);
+    let pool = roll.pool.clone().unwrap();
+    assert_eq!(net, -3);
+    assert!(pool.is_botch);
+    assert!(!pool.is_glitch);
+
+    let net = roll.count_successes((&ComparisonArg::GreaterThan, 10), Some((&ComparisonArg::LessThanOrEqual, 5)), None);
+    let pool = roll.pool.clone().unwrap();
+    assert_eq!(net, -4);
+    assert!(pool.is_glitch);
+    assert!(pool.is_critical_glitch);
+}
+
+#[test]
+fn it_can_keep_and_drop_dice() {
+    let mut rng = SeededRng::new(1);
+    let mut roll = Roll::new(RollFlags::new(), &mut rng).unwrap();
+    for value in vec![3, 6, 1, 5, 2, 4] {
+        let mut die = Die::new(DieType::D6);
+        die.value = value;
+        roll.dice.push(die);
+    }
+
+    // 6d6kh4dl1 keeps 6, 5, 4 and 3, then drops the 3
+    roll.keep_high(4);
+    roll.drop_low(1);
+    let kept: Vec<i16> = roll.dice.iter().filter(|d| !d.is_dropped).map(|d| d.value).collect();
+    assert_eq!(kept, vec![6, 5, 4]);
+
+    roll.drop_high(1);
+    let kept: Vec<i16> = roll.dice.iter().filter(|d| !d.is_dropped).map(|d| d.value).collect();
+    assert_eq!(kept, vec![5, 4]);
+
+    // Dice that were already dropped do not count towards what is kept
+    roll.keep_low(1);
+    let kept: Vec<i16> = roll.dice.iter().filter(|d| !d.is_dropped).map(|d| d.value).collect();
+    assert_eq!(kept, vec![4]);
+}
+
+#[test]
+fn it_can_combine_keep_and_drop_flags() {
+    let mut rng = SeededRng::new(1);
+    let mut flags = RollFlags::new();
+    flags.n = 6;
+    flags.max = 6;
+    flags.sides = Some(vec![4]);
+    flags.kh = 4;
+    flags.dl = 1;
+
+    let roll = Roll::new(flags, &mut rng).unwrap();
+    assert_eq!(roll.dice.iter().filter(|d| !d.is_dropped).count(), 3);
+    assert_eq!(roll.value, 12);
+}
+
+#[test]
+fn it_can_penetrate_dice() {
+    let mut rng = SeededRng::new(1);
+    let mut flags = RollFlags::new();
+    flags.n = 1;
+    flags.max = 6;
+    flags.sides = Some(vec![6]);
+    flags.explode = 6;
+    flags.explode_op = Some(ComparisonArg::GreaterThanOrEqual);
+    flags.explosion = Explosion::Penetrate;
+
+    let roll = Roll::new(flags, &mut rng).unwrap();
+    assert_eq!(roll.dice.len(), 1 + MAX_EXPLOSIONS as usize);
+    assert_eq!(roll.dice[0].value, 6);
+    assert_eq!(roll.dice[1].value, 5);
+    assert_eq!(roll.dice[1].partials, vec![6]);
+    assert_eq!(roll.value, 6 + 5 * MAX_EXPLOSIONS as i32);
+}
+
+#[test]
+fn it_can_mark_criticals() {
+    // Dice are critical on their highest face and fumble on their lowest by default
+    let mut rng = FixedRng::new(vec![20, 1, 10]);
+    let mut flags = RollFlags::new();
+    flags.n = 3;
+    flags.die = DieType::D20;
+    flags.max = 20;
+    let roll = Roll::new(flags, &mut rng).unwrap();
+    assert_eq!(roll.dice.iter().map(|d| (d.is_critical, d.is_fumble)).collect::<Vec<(bool, bool)>>(), vec![(true, false), (false, true), (false, false)]);
+    assert_eq!(roll.criticals, 1);
+    assert_eq!(roll.fumbles, 1);
+    assert!(roll.is_critical && roll.is_fumble);
+
+    // Dropped dice don't count towards the roll
+    let mut rng = FixedRng::new(vec![20, 1, 10]);
+    let mut flags = RollFlags::new();
+    flags.n = 3;
+    flags.max = 20;
+    flags.kh = 1;
+    flags.critical = 19;
+    flags.critical_op = Some(ComparisonArg::GreaterThanOrEqual);
+    let roll = Roll::new(flags, &mut rng).unwrap();
+    assert!(roll.is_critical);
+    assert!(!roll.is_fumble);
+    assert_eq!(roll.fumbles, 0);
+
+    // Custom sides use their highest and lowest faces
+    let mut rng = FixedRng::new(vec![0, 1]);
+    let mut flags = RollFlags::new();
+    flags.n = 1;
+    flags.sides = Some(vec![2, 4, 6]);
+    let roll = Roll::new(flags, &mut rng).unwrap();
+    assert!(roll.is_fumble);
+
+    // Only the die that was rolled is critical, not the bonus dice it exploded into
+    let mut rng = FixedRng::new(vec![6, 6, 1]);
+    let mut flags = RollFlags::new();
+    flags.n = 1;
+    flags.max = 6;
+    flags.explode = 6;
+    flags.explode_op = Some(ComparisonArg::GreaterThanOrEqual);
+    let roll = Roll::new(flags, &mut rng).unwrap();
+    assert_eq!(roll.dice.iter().map(|d| (d.is_critical, d.is_fumble)).collect::<Vec<(bool, bool)>>(), vec![(true, false), (false, false), (false, false)]);
+    assert_eq!(roll.criticals, 1);
+    assert_eq!(roll.fumbles, 0);
+}
+
+#[test]
+fn it_can_limit_rerolls() {
+    // A die that always rolls a face it rerolls stops at the cap
+    let mut rng = FixedRng::new(vec![6]);
+    let mut flags = RollFlags::new();
+    flags.n = 1;
+    flags.max = 6;
+    flags.rr = 6;
+    flags.rr_op = Some(ComparisonArg::EqualTo);
+    match Roll::new(flags, &mut rng) {
+        Err(RollError::LimitExceeded { .. }) => {}, // do nothing
+        _ => panic!("Rerolling without end should exceed the limit"),
+    }
+
+    // Only the dice that still match are rerolled
+    let mut rng = FixedRng::new(vec![1, 1, 4, 5]);
+    let mut flags = RollFlags::new();
+    flags.n = 2;
+    flags.max = 6;
+    flags.rr = 1;
+    flags.rr_op = Some(ComparisonArg::EqualTo);
+    let roll = Roll::new(flags, &mut rng).unwrap();
+    assert_eq!(roll.dice.len(), 4);
+    assert_eq!(roll.value, 9);
+}
@@ -0,0 +1,244 @@
+use chrono::DateTime;
+use chrono::prelude::Utc;
+use error::RollError;
+#[cfg(test)]
+use expression::Rounding;
+use hex;
+use rand::{OsRng, Rng};
+use receipt;
+#[cfg(test)]
+use rng::SeededRng;
+use roll::Roll;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use uuid::Uuid;
+
+/// Who can see a roll made in a room
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Visibility {
+    /// Everyone in the room
+    Public,
+
+    /// Only the roller and the game masters
+    Gm,
+
+    /// Only the roller and the members it was whispered to
+    Whisper,
+}
+
+impl Default for Visibility {
+    fn default() -> Visibility {
+        Visibility::Public
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Member {
+    /// If the member is a game master and sees GM-only rolls
+    pub is_gm: bool,
+
+    /// Name of the member, unique in the room so rolls can be whispered to them
+    pub name: String,
+
+    /// Secret needed to roll in and listen to the room, only given to the member when they join
+    pub token: String,
+}
+
+/// Who can see a roll made in a room
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Audience {
+    /// Name of the member who made the roll
+    pub member: String,
+
+    /// Names of the members a whispered roll was sent to
+    pub recipients: Vec<String>,
+
+    pub room_id: String,
+    pub visibility: Visibility,
+}
+
+impl Audience {
+    /// If a member of the room can see the roll
+    pub fn can_see(&self, member: &Member) -> bool {
+        if member.name == self.member {
+            return true;
+        }
+        match self.visibility {
+            Visibility::Public => true,
+            Visibility::Gm => member.is_gm,
+            Visibility::Whisper => self.recipients.iter().any(|recipient| *recipient == member.name),
+        }
+    }
+}
+
+/// A roll made in a room, sent to every member who can see it
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct RoomEvent {
+    #[serde(flatten)]
+    pub audience: Audience,
+
+    pub roll: Roll,
+}
+
+/// A table of players sharing their rolls
+pub struct Room {
+    /// Unique identifier of the room
+    pub id: String,
+
+    pub members: Vec<Member>,
+    pub name: String,
+
+    /// Member name and channel of everyone listening to the room
+    subscribers: Vec<(String, Sender<RoomEvent>)>,
+
+    /// Timestamp of when the room was created
+    pub timestamp: DateTime<Utc>,
+}
+
+impl Room {
+    pub fn new(name: &str) -> Room {
+        Room {
+            id: Uuid::new_v4().to_string(),
+            members: vec![],
+            name: name.to_string(),
+            subscribers: vec![],
+            timestamp: Utc::now(),
+        }
+    }
+
+    /// Add a member to the room, giving them a new secret token
+    pub fn join(&mut self, name: &str, is_gm: bool) -> Result<Member, RollError> {
+        let name = name.trim();
+        if name.is_empty() {
+            return Err(RollError::InvalidRange { offset: 0, message: "Members need a name".to_string() });
+        }
+        if self.member(name).is_some() {
+            return Err(RollError::InvalidRange {
+                offset: 0,
+                message: format!("\"{}\" is taken, members need a unique name", name),
+            });
+        }
+        let mut bytes = [0u8; 32];
+        OsRng::new()
+            .map_err(|e| RollError::Random { offset: 0, message: e.to_string() })?
+            .fill_bytes(&mut bytes);
+        let member = Member {
+            is_gm,
+            name: name.to_string(),
+            token: hex::encode(&bytes[..]),
+        };
+        self.members.push(member.clone());
+        Ok(member)
+    }
+
+    /// Find a member by name
+    pub fn member(&self, name: &str) -> Option<&Member> {
+        self.members.iter().find(|member| member.name == name)
+    }
+
+    /// Find the member a token was given to
+    ///
+    /// Every token is compared in constant time, so a token can't be guessed from how long it
+    /// takes to be rejected.
+    pub fn authenticate(&self, token: &str) -> Option<&Member> {
+        let mut found = None;
+        for member in self.members.iter() {
+            if receipt::constant_time_eq(member.token.as_bytes(), token.as_bytes()) {
+                found = Some(member);
+            }
+        }
+        found
+    }
+
+    /// Listen for the rolls a member can see, returns `None` if the token isn't a member's
+    pub fn subscribe(&mut self, token: &str) -> Option<Receiver<RoomEvent>> {
+        let name = self.authenticate(token)?.name.clone();
+        let (sender, receiver) = channel();
+        self.subscribers.push((name, sender));
+        Some(receiver)
+    }
+
+    /// If a member can see a roll
+    pub fn can_see(&self, name: &str, event: &RoomEvent) -> bool {
+        match self.member(name) {
+            Some(member) => event.audience.can_see(member),
+            None => false,
+        }
+    }
+
+    /// Send a roll to every subscriber who can see it, returning how many it was sent to
+    ///
+    /// Subscribers that have stopped listening are removed.
+    pub fn broadcast(&mut self, event: &RoomEvent) -> usize {
+        let subscribers: Vec<(String, Sender<RoomEvent>)> = self.subscribers.drain(..).collect();
+        let mut sent = 0;
+        for (name, sender) in subscribers.into_iter() {
+            if self.can_see(&name, event) {
+                if sender.send(event.clone()).is_err() {
+                    continue;
+                }
+                sent += 1;
+            }
+            self.subscribers.push((name, sender));
+        }
+        sent
+    }
+}
+
+#[test]
+fn it_can_broadcast_rolls() {
+    let mut room = Room::new("Tomb of Horrors");
+    let gm = room.join("Dungeon Master", true).unwrap();
+    let alice = room.join("Alice", false).unwrap();
+    let bob = room.join("Bob", false).unwrap();
+
+    // Names are public, only tokens let a member listen in
+    assert!(room.subscribe("Dungeon Master").is_none());
+    assert!(room.subscribe("").is_none());
+
+    let gm_events = room.subscribe(&gm.token).unwrap();
+    let alice_events = room.subscribe(&alice.token).unwrap();
+    let bob_events = room.subscribe(&bob.token).unwrap();
+
+    let roll = ::roll_with("1d20", Rounding::Floor, &mut SeededRng::new(1)).unwrap();
+    let event = |visibility, recipients: Vec<String>| RoomEvent {
+        audience: Audience {
+            member: alice.name.clone(),
+            recipients,
+            room_id: room.id.clone(),
+            visibility,
+        },
+        roll: roll.clone(),
+    };
+
+    let public = event(Visibility::Public, vec![]);
+    let secret = event(Visibility::Gm, vec![]);
+    let whisper = event(Visibility::Whisper, vec![bob.name.clone()]);
+    assert_eq!(room.broadcast(&public), 3);
+    assert_eq!(room.broadcast(&secret), 2);
+    assert_eq!(room.broadcast(&whisper), 2);
+
+    let visibilities = |events: &Receiver<RoomEvent>| events.try_iter().map(|e| e.audience.visibility).collect::<Vec<Visibility>>();
+    assert_eq!(visibilities(&gm_events), vec![Visibility::Public, Visibility::Gm]);
+    assert_eq!(visibilities(&alice_events), vec![Visibility::Public, Visibility::Gm, Visibility::Whisper]);
+    assert_eq!(visibilities(&bob_events), vec![Visibility::Public, Visibility::Whisper]);
+
+    // Subscribers that stop listening are dropped
+    drop(bob_events);
+    assert_eq!(room.broadcast(&public), 2);
+    assert_eq!(room.subscribers.len(), 2);
+}
+
+#[test]
+fn it_can_authenticate_members() {
+    let mut room = Room::new("Tomb of Horrors");
+    let gm = room.join("Dungeon Master", true).unwrap();
+    let alice = room.join("Alice", false).unwrap();
+    assert_ne!(gm.token, alice.token);
+    assert_eq!(room.authenticate(&alice.token).map(|member| member.name.as_str()), Some("Alice"));
+    assert!(room.authenticate(&alice.token[1..]).is_none());
+
+    // Names must be unique so whispers reach the right member
+    assert!(room.join("Alice", false).is_err());
+    assert!(room.join(" ", false).is_err());
+}
@@ -0,0 +1,129 @@
+use chrono::DateTime;
+use chrono::prelude::Utc;
+#[cfg(test)]
+use chrono::Duration;
+#[cfg(test)]
+use expression::Rounding;
+use hex;
+use rand::{OsRng, Rng};
+use receipt::{self, Receipt};
+#[cfg(test)]
+use rng::SeededRng;
+use roll::Roll;
+use sha2::{Digest, Sha256};
+use std::io;
+
+/// Keeps a roll's result hidden until it is revealed, e.g. a secret perception check
+///
+/// Only the roll's id and the commitment are published when the dice are rolled. The commitment is
+/// the SHA-256 hash of a random salt and the roll's receipt, so the result can't be guessed from it
+/// and can't be changed without breaking it. Once revealed, anyone can check the roll with `verify`.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Seal {
+    /// SHA-256 hash of the salt and the roll's receipt, published up front
+    pub commitment: String,
+
+    /// If the roll has been revealed with its key
+    pub is_revealed: bool,
+
+    /// Secret needed to reveal the roll before its deadline
+    pub key: String,
+
+    /// When the roll is revealed on its own, if ever
+    pub reveal_at: Option<DateTime<Utc>>,
+
+    /// Random salt hashed with the receipt, so the commitment doesn't give away small results
+    pub salt: String,
+}
+
+fn random_hex() -> io::Result<String> {
+    let mut bytes = [0u8; 32];
+    OsRng::new()?.fill_bytes(&mut bytes);
+    Ok(hex::encode(&bytes[..]))
+}
+
+impl Seal {
+    /// Seal a roll, revealing it at the deadline if there is one
+    pub fn new(roll: &Roll, reveal_at: Option<DateTime<Utc>>) -> io::Result<Seal> {
+        let salt = random_hex()?;
+        Ok(Seal {
+            commitment: commit(roll, &salt),
+            is_revealed: false,
+            key: random_hex()?,
+            reveal_at,
+            salt,
+        })
+    }
+
+    /// If the deadline has passed, so the roll is revealed without its key
+    pub fn is_due(&self, now: DateTime<Utc>) -> bool {
+        match self.reveal_at {
+            Some(reveal_at) => now >= reveal_at,
+            None => false,
+        }
+    }
+
+    /// If the roll is still hidden, neither revealed with its key nor past its deadline
+    pub fn is_hidden(&self, now: DateTime<Utc>) -> bool {
+        !self.is_revealed && !self.is_due(now)
+    }
+
+    /// If a key is the one needed to reveal the roll, compared in constant time
+    pub fn is_key(&self, key: &str) -> bool {
+        receipt::constant_time_eq(self.key.as_bytes(), key.as_bytes())
+    }
+
+    /// Reveal the roll, returning `true` if it wasn't revealed before
+    pub fn reveal(&mut self) -> bool {
+        let was_sealed = !self.is_revealed;
+        self.is_revealed = true;
+        was_sealed
+    }
+}
+
+/// Hash the salt and the receipt of a roll into its commitment
+pub fn commit(roll: &Roll, salt: &str) -> String {
+    let mut hasher = Sha256::default();
+    hasher.input(salt.as_bytes());
+    hasher.input(b":");
+    hasher.input(Receipt::new(roll).canonical().as_bytes());
+    hex::encode(hasher.result().as_slice())
+}
+
+/// Check a revealed roll against the commitment published when it was sealed
+pub fn verify(roll: &Roll, salt: &str, commitment: &str) -> bool {
+    commit(roll, salt) == commitment
+}
+
+#[test]
+fn it_can_seal_rolls() {
+    let roll = ::roll_with("1d20+3", Rounding::Floor, &mut SeededRng::new(1)).unwrap();
+    let mut seal = Seal::new(&roll, None).unwrap();
+    assert!(verify(&roll, &seal.salt, &seal.commitment));
+
+    // Changing the roll or the salt breaks the commitment
+    let mut forged = roll.clone();
+    forged.value += 1;
+    assert!(!verify(&forged, &seal.salt, &seal.commitment));
+    assert!(!verify(&roll, "salt", &seal.commitment));
+
+    let key = seal.key.clone();
+    assert!(seal.is_key(&key));
+    assert!(!seal.is_key(&key[1..]));
+
+    assert!(seal.is_hidden(Utc::now()));
+    assert!(seal.reveal());
+    assert!(!seal.reveal());
+    assert!(!seal.is_hidden(Utc::now()));
+}
+
+#[test]
+fn it_can_reveal_rolls_after_a_deadline() {
+    let roll = ::roll_with("1d20", Rounding::Floor, &mut SeededRng::new(1)).unwrap();
+    let now = Utc::now();
+    let seal = Seal::new(&roll, Some(now + Duration::minutes(5))).unwrap();
+    assert!(!seal.is_due(now));
+    assert!(seal.is_hidden(now));
+    assert!(seal.is_due(now + Duration::minutes(5)));
+    assert!(!seal.is_hidden(now + Duration::minutes(5)));
+}
@@ -0,0 +1,192 @@
+use command;
+use error::RollError;
+use expression::Rounding;
+use rng::DiceRng;
+#[cfg(test)]
+use rng::SeededRng;
+#[cfg(test)]
+use roll_with;
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+/// z-score of a two-sided 95% confidence interval
+const Z_95: f64 = 1.959964;
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Bin {
+    /// Number of trials that rolled this value
+    pub count: u32,
+
+    /// Fraction of trials that rolled this value
+    pub frequency: f64,
+
+    pub value: i32,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ConfidenceInterval {
+    pub lower: f64,
+    pub upper: f64,
+}
+
+/// Results of rolling a command many times
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Simulation {
+    /// Every value that was rolled, lowest first
+    pub histogram: Vec<Bin>,
+
+    /// If the time limit was reached before every trial was rolled
+    pub is_time_limited: bool,
+
+    pub max: i32,
+    pub mean: f64,
+
+    /// 95% confidence interval of the mean
+    pub mean_confidence_interval: ConfidenceInterval,
+
+    pub min: i32,
+
+    /// Range holding the middle 95% of the rolls
+    pub result_interval: ConfidenceInterval,
+
+    pub std_dev: f64,
+
+    /// Number of trials actually rolled
+    pub trials: u32,
+}
+
+/// Roll a command up to `trials` times, stopping early once `time_limit` has passed
+///
+/// The command is parsed once, then every trial rolls it exactly as a live roll would, so the results
+/// match what the roll endpoint returns. Dice, explosions and rerolls are capped, so a single trial
+/// can't run on long past the time limit. Fails on the first trial that can't be rolled.
+pub fn simulate<R: DiceRng + ?Sized>(command: &str, rounding: Rounding, rng: &mut R, trials: u32, time_limit: Duration) -> Result<Simulation, RollError> {
+    let start = Instant::now();
+    simulate_with_clock(command, rounding, rng, trials, time_limit, || start.elapsed())
+}
+
+/// Like `simulate`, reading the time passed since the first trial from `elapsed`
+fn simulate_with_clock<R, C>(command: &str, rounding: Rounding, rng: &mut R, trials: u32, time_limit: Duration, mut elapsed: C) -> Result<Simulation, RollError>
+    where R: DiceRng + ?Sized, C: FnMut() -> Duration
+{
+    let parsed = command::parse(command)?;
+    let mut counts: BTreeMap<i32, u32> = BTreeMap::new();
+    let mut rolled = 0;
+    let mut is_time_limited = false;
+    while rolled < trials {
+        if elapsed() > time_limit {
+            is_time_limited = true;
+            break;
+        }
+        let roll = parsed.clone().roll(rounding, rng)?;
+        *counts.entry(roll.value).or_insert(0) += 1;
+        rolled += 1;
+    }
+    if rolled == 0 {
+        return Err(RollError::LimitExceeded {
+            offset: 0,
+            message: "No trials were rolled before the time limit".to_string(),
+        });
+    }
+
+    let n = rolled as f64;
+    let mean = counts.iter().fold(0.0, |sum, (&v, &c)| sum + v as f64 * c as f64) / n;
+    let variance = if rolled > 1 {
+        counts.iter().fold(0.0, |sum, (&v, &c)| sum + (v as f64 - mean).powi(2) * c as f64) / (n - 1.0)
+    } else {
+        0.0
+    };
+    let std_dev = variance.sqrt();
+    let margin = Z_95 * std_dev / n.sqrt();
+
+    Ok(Simulation {
+        histogram: counts.iter().map(|(&value, &count)| Bin {
+            count,
+            frequency: count as f64 / n,
+            value,
+        }).collect(),
+        is_time_limited,
+        max: *counts.keys().next_back().unwrap(),
+        mean,
+        mean_confidence_interval: ConfidenceInterval {
+            lower: mean - margin,
+            upper: mean + margin,
+        },
+        min: *counts.keys().next().unwrap(),
+        result_interval: ConfidenceInterval {
+            lower: quantile(&counts, rolled, 0.025) as f64,
+            upper: quantile(&counts, rolled, 0.975) as f64,
+        },
+        std_dev,
+        trials: rolled,
+    })
+}
+
+/// Smallest value with at least `fraction` of the trials at or below it
+fn quantile(counts: &BTreeMap<i32, u32>, trials: u32, fraction: f64) -> i32 {
+    let target = (fraction * trials as f64).ceil().max(1.0) as u32;
+    let mut cumulative = 0;
+    for (&value, &count) in counts.iter() {
+        cumulative += count;
+        if cumulative >= target {
+            return value;
+        }
+    }
+    *counts.keys().next_back().unwrap()
+}
+
+#[test]
+fn it_can_simulate_rolls() {
+    let mut rng = SeededRng::new(1);
+    let simulation = simulate("2d6", Rounding::Floor, &mut rng, 20000, Duration::from_secs(60)).unwrap();
+    assert_eq!(simulation.trials, 20000);
+    assert!(!simulation.is_time_limited);
+    assert_eq!(simulation.min, 2);
+    assert_eq!(simulation.max, 12);
+    assert_eq!(simulation.histogram.iter().map(|b| b.count).sum::<u32>(), 20000);
+    assert!(simulation.mean_confidence_interval.lower < 7.0 && simulation.mean_confidence_interval.upper > 7.0);
+    assert!((simulation.std_dev - (35.0f64 / 6.0).sqrt()).abs() < 0.05);
+
+    // 3d6 rolls 4 or less 1.9% of the time and 16 or less 98.1% of the time
+    let three_d6 = simulate("3d6", Rounding::Floor, &mut rng, 20000, Duration::from_secs(60)).unwrap();
+    assert_eq!(three_d6.result_interval.lower, 5.0);
+    assert_eq!(three_d6.result_interval.upper, 16.0);
+
+    // Exploding dice can only be simulated
+    let exploding = simulate("1d6!", Rounding::Floor, &mut rng, 20000, Duration::from_secs(60)).unwrap();
+    assert!(exploding.max > 6);
+    assert!((exploding.mean - 4.2).abs() < 0.1);
+}
+
+#[test]
+fn it_matches_live_rolls() {
+    let simulation = simulate("4d6kh3+1d20", Rounding::Floor, &mut SeededRng::new(42), 1, Duration::from_secs(60)).unwrap();
+    let roll = roll_with("4d6kh3+1d20", Rounding::Floor, &mut SeededRng::new(42)).unwrap();
+    assert_eq!(simulation.min, roll.value);
+}
+
+#[test]
+fn it_stops_at_the_time_limit() {
+    let mut rng = SeededRng::new(1);
+
+    // Each trial takes 10ms on the clock, so only 5 fit in 50ms
+    let mut now = Duration::from_millis(0);
+    let clock = || {
+        now += Duration::from_millis(10);
+        now
+    };
+    let simulation = simulate_with_clock("1d20", Rounding::Floor, &mut rng, 100, Duration::from_millis(50), clock).unwrap();
+    assert!(simulation.is_time_limited);
+    assert_eq!(simulation.trials, 5);
+
+    let error = simulate_with_clock("1d20", Rounding::Floor, &mut rng, 100, Duration::from_millis(0), || Duration::from_millis(10)).unwrap_err();
+    assert!(error.message().starts_with("No trials"));
+
+    assert!(simulate("not dice", Rounding::Floor, &mut rng, 10, Duration::from_secs(1)).is_err());
+
+    // A trial that would reroll without end stops at the cap instead of running past the limit
+    match simulate("1000d20rr<=19", Rounding::Floor, &mut rng, 10, Duration::from_secs(60)) {
+        Err(RollError::LimitExceeded { .. }) => {}, // do nothing
+        _ => panic!("Rerolling too many dice should exceed the limit"),
+    }
+}
@@ -0,0 +1,549 @@
+use chrono::DateTime;
+use chrono::prelude::Utc;
+#[cfg(test)]
+use expression::Rounding;
+#[cfg(test)]
+use rng::SeededRng;
+use roll::Roll;
+use room::{Audience, Member, Visibility};
+use seal::Seal;
+use serde_json;
+use std::collections::{HashMap, VecDeque};
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, RwLock};
+
+/// A member of a room looking at the rolls made in it
+#[derive(Clone, Debug)]
+pub struct Viewer {
+    pub member: Member,
+    pub room_id: String,
+}
+
+/// Which rolls to list, newest first
+#[derive(Clone, Debug, Default)]
+pub struct RollQuery {
+    /// Only rolls made at or after this time
+    pub after: Option<DateTime<Utc>>,
+
+    /// Only rolls made before this time
+    pub before: Option<DateTime<Utc>>,
+
+    /// Only rolls with this label
+    pub label: Option<String>,
+
+    /// Most rolls to return
+    pub limit: usize,
+
+    /// Number of matching rolls to skip
+    pub offset: usize,
+
+    /// Only the rolls of the viewer's room they can see, otherwise only rolls everyone can see
+    pub viewer: Option<Viewer>,
+}
+
+impl RollQuery {
+    fn matches(&self, timestamp: DateTime<Utc>, label: Option<&String>) -> bool {
+        let after = match self.after {
+            Some(after) => timestamp >= after,
+            None => true,
+        };
+        let before = match self.before {
+            Some(before) => timestamp < before,
+            None => true,
+        };
+        let label = match self.label {
+            Some(ref wanted) => label == Some(wanted),
+            None => true,
+        };
+        after && before && label
+    }
+}
+
+/// If a roll can be seen, by a member of a room or by anyone when there is no viewer
+///
+/// Hidden sealed rolls can't be seen by anyone. Rolls made in a room are only listed for its members,
+/// except public ones which anyone can see.
+fn is_visible(seal: &Option<Seal>, audience: &Option<Audience>, viewer: Option<&Viewer>, now: DateTime<Utc>) -> bool {
+    if let &Some(ref seal) = seal {
+        if seal.is_hidden(now) {
+            return false;
+        }
+    }
+    match (audience, viewer) {
+        (&Some(ref audience), Some(viewer)) => audience.room_id == viewer.room_id && audience.can_see(&viewer.member),
+        (&Some(ref audience), None) => audience.visibility == Visibility::Public,
+        (&None, Some(_)) => false,
+        (&None, None) => true,
+    }
+}
+
+/// A roll as it is kept in the history
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Record {
+    #[serde(flatten)]
+    pub roll: Roll,
+
+    /// Set when the roll was made in a room, only the members who could see it can look it up
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub audience: Option<Audience>,
+
+    /// Set when the roll was sealed, it is left out of the history while it is hidden
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seal: Option<Seal>,
+}
+
+impl Record {
+    pub fn new(roll: Roll) -> Record {
+        Record {
+            roll,
+            audience: None,
+            seal: None,
+        }
+    }
+
+    /// If the roll is sealed and still hidden
+    pub fn is_hidden(&self, now: DateTime<Utc>) -> bool {
+        match self.seal {
+            Some(ref seal) => seal.is_hidden(now),
+            None => false,
+        }
+    }
+
+    /// If a member of a room can see the roll, or anyone when there is no viewer
+    pub fn is_visible_to(&self, viewer: Option<&Viewer>, now: DateTime<Utc>) -> bool {
+        is_visible(&self.seal, &self.audience, viewer, now)
+    }
+}
+
+/// A page of rolls from a query
+#[derive(Clone, Debug, Serialize)]
+pub struct Page {
+    pub rolls: Vec<Roll>,
+
+    /// Number of rolls matching the query across every page
+    pub total: usize,
+}
+
+/// Somewhere to record every roll so it can be looked up by id later
+pub trait RollStore: Send + Sync {
+    /// Record a roll, replacing any record with the same id (e.g. when a sealed roll is revealed)
+    fn save(&self, record: &Record) -> io::Result<()>;
+
+    /// Find a roll by its id, even if it is hidden
+    fn get(&self, id: &str) -> io::Result<Option<Record>>;
+
+    /// List the rolls matching a query that can be seen, newest first
+    fn list(&self, query: &RollQuery) -> io::Result<Page>;
+}
+
+struct History {
+    /// Number of rolls forgotten to stay under the limit, so positions in `ids` stay the same
+    forgotten: usize,
+
+    /// Position of each roll, counting forgotten rolls, by id
+    ids: HashMap<String, usize>,
+
+    /// The most recent rolls in the order they were first saved
+    records: VecDeque<Record>,
+}
+
+/// Keeps the most recent rolls in memory, they are lost when the server stops
+pub struct MemoryStore {
+    history: RwLock<History>,
+
+    /// Most rolls kept, the oldest are forgotten first
+    max_rolls: usize,
+}
+
+impl MemoryStore {
+    pub fn new(max_rolls: usize) -> MemoryStore {
+        MemoryStore {
+            history: RwLock::new(History {
+                forgotten: 0,
+                ids: HashMap::new(),
+                records: VecDeque::new(),
+            }),
+            max_rolls: max_rolls.max(1),
+        }
+    }
+}
+
+impl RollStore for MemoryStore {
+    fn save(&self, record: &Record) -> io::Result<()> {
+        let mut history = self.history.write().unwrap();
+        if let Some(&position) = history.ids.get(&record.roll.id) {
+            let index = position - history.forgotten;
+            history.records[index] = record.clone();
+            return Ok(());
+        }
+        let position = history.forgotten + history.records.len();
+        history.ids.insert(record.roll.id.clone(), position);
+        history.records.push_back(record.clone());
+
+        if history.records.len() > self.max_rolls {
+            if let Some(oldest) = history.records.pop_front() {
+                history.ids.remove(&oldest.roll.id);
+                history.forgotten += 1;
+            }
+        }
+        Ok(())
+    }
+
+    fn get(&self, id: &str) -> io::Result<Option<Record>> {
+        let history = self.history.read().unwrap();
+        Ok(history.ids.get(id).map(|&position| history.records[position - history.forgotten].clone()))
+    }
+
+    fn list(&self, query: &RollQuery) -> io::Result<Page> {
+        let history = self.history.read().unwrap();
+        let now = Utc::now();
+        let matching: Vec<&Roll> = history.records.iter().rev()
+            .filter(|record| record.is_visible_to(query.viewer.as_ref(), now))
+            .map(|record| &record.roll)
+            .filter(|roll| query.matches(roll.timestamp, roll.label.as_ref()))
+            .collect();
+        Ok(Page {
+            rolls: matching.iter().skip(query.offset).take(query.limit).map(|roll| (*roll).clone()).collect(),
+            total: matching.len(),
+        })
+    }
+}
+
+/// Where a roll is in the file, with what queries need to know so the file isn't read to filter it
+struct Entry {
+    audience: Option<Audience>,
+    label: Option<String>,
+
+    /// Byte offset of the roll's line in the file
+    offset: u64,
+
+    seal: Option<Seal>,
+    timestamp: DateTime<Utc>,
+}
+
+impl Entry {
+    fn new(record: &Record, offset: u64) -> Entry {
+        Entry {
+            audience: record.audience.clone(),
+            label: record.roll.label.clone(),
+            offset,
+            seal: record.seal.clone(),
+            timestamp: record.roll.timestamp,
+        }
+    }
+}
+
+struct Index {
+    entries: Vec<Entry>,
+
+    /// Position of each roll in `entries`, by id
+    ids: HashMap<String, usize>,
+}
+
+impl Index {
+    fn insert(&mut self, id: &str, entry: Entry) {
+        if let Some(&position) = self.ids.get(id) {
+            self.entries[position] = entry;
+            return;
+        }
+        self.ids.insert(id.to_string(), self.entries.len());
+        self.entries.push(entry);
+    }
+}
+
+/// Appends rolls to a file, one JSON object per line, so they survive a restart
+///
+/// Only where each roll is in the file and what queries filter on are kept in memory, rolls are read
+/// from the file a page at a time. A roll saved again is appended again, and the last line with its
+/// id wins.
+pub struct FileStore {
+    file: Mutex<File>,
+    index: RwLock<Index>,
+    path: PathBuf,
+}
+
+fn invalid_data(error: serde_json::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+impl FileStore {
+    /// Open the file, creating it if it doesn't exist
+    ///
+    /// A roll the server didn't finish writing before it stopped is cut off the end of the file, so
+    /// the next roll starts on a line of its own. Any other line that can't be read fails.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<FileStore> {
+        let mut index = Index { entries: vec![], ids: HashMap::new() };
+        let mut incomplete = None;
+        if path.as_ref().exists() {
+            let mut reader = BufReader::new(File::open(&path)?);
+            let mut offset = 0;
+            loop {
+                let mut line = String::new();
+                let read = reader.read_line(&mut line)?;
+                if read == 0 {
+                    break;
+                }
+                if !line.trim().is_empty() {
+                    let record = serde_json::from_str::<Record>(&line);
+                    let is_complete = line.ends_with('\n') && record.is_ok();
+                    if !is_complete && reader.fill_buf()?.is_empty() {
+                        incomplete = Some(offset);
+                        break;
+                    }
+                    let record = record.map_err(invalid_data)?;
+                    index.insert(&record.roll.id, Entry::new(&record, offset));
+                }
+                offset += read as u64;
+            }
+        }
+        if let Some(length) = incomplete {
+            eprintln!("Dropping an incomplete roll at byte {} of {}", length, path.as_ref().display());
+            OpenOptions::new().write(true).open(&path)?.set_len(length)?;
+        }
+
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        Ok(FileStore {
+            file: Mutex::new(file),
+            index: RwLock::new(index),
+            path: path.as_ref().to_path_buf(),
+        })
+    }
+
+    /// Read the roll on the line starting at an offset
+    fn read(&self, reader: &mut BufReader<File>, offset: u64) -> io::Result<Record> {
+        reader.seek(SeekFrom::Start(offset))?;
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+        serde_json::from_str(&line).map_err(invalid_data)
+    }
+}
+
+impl RollStore for FileStore {
+    fn save(&self, record: &Record) -> io::Result<()> {
+        let line = serde_json::to_string(record).map_err(invalid_data)?;
+        let offset = {
+            let mut file = self.file.lock().unwrap();
+            let offset = file.seek(SeekFrom::End(0))?;
+            file.write_all((line + "\n").as_bytes())?;
+            file.flush()?;
+            offset
+        };
+        self.index.write().unwrap().insert(&record.roll.id, Entry::new(record, offset));
+        Ok(())
+    }
+
+    fn get(&self, id: &str) -> io::Result<Option<Record>> {
+        let offset = {
+            let index = self.index.read().unwrap();
+            match index.ids.get(id) {
+                Some(&position) => index.entries[position].offset,
+                None => return Ok(None),
+            }
+        };
+        let mut reader = BufReader::new(File::open(&self.path)?);
+        self.read(&mut reader, offset).map(Some)
+    }
+
+    fn list(&self, query: &RollQuery) -> io::Result<Page> {
+        let now = Utc::now();
+        let (offsets, total) = {
+            let index = self.index.read().unwrap();
+            let matching: Vec<u64> = index.entries.iter().rev()
+                .filter(|entry| is_visible(&entry.seal, &entry.audience, query.viewer.as_ref(), now))
+                .filter(|entry| query.matches(entry.timestamp, entry.label.as_ref()))
+                .map(|entry| entry.offset)
+                .collect();
+            let offsets: Vec<u64> = matching.iter().skip(query.offset).take(query.limit).cloned().collect();
+            (offsets, matching.len())
+        };
+
+        let mut reader = BufReader::new(File::open(&self.path)?);
+        let mut rolls = vec![];
+        for offset in offsets.into_iter() {
+            rolls.push(self.read(&mut reader, offset)?.roll);
+        }
+        Ok(Page { rolls, total })
+    }
+}
+
+#[cfg(test)]
+fn labelled(label: Option<&str>, rng: &mut SeededRng) -> Roll {
+    let mut roll = ::roll_with("1d20", Rounding::Floor, rng).unwrap();
+    roll.label = label.map(|label| label.to_string());
+    roll
+}
+
+#[test]
+fn it_can_store_rolls_in_memory() {
+    let mut rng = SeededRng::new(1);
+    let store = MemoryStore::new(100);
+    let first = labelled(Some("Attack"), &mut rng);
+    let second = labelled(None, &mut rng);
+    let third = labelled(Some("Attack"), &mut rng);
+    for roll in [&first, &second, &third].iter() {
+        store.save(&Record::new((*roll).clone())).unwrap();
+    }
+
+    assert_eq!(store.get(&second.id).unwrap().unwrap().roll.value, second.value);
+    assert!(store.get("missing").unwrap().is_none());
+
+    // Newest first, filtered and paginated
+    let page = store.list(&RollQuery { limit: 2, ..RollQuery::default() }).unwrap();
+    assert_eq!(page.total, 3);
+    assert_eq!(page.rolls.iter().map(|r| r.id.clone()).collect::<Vec<String>>(), vec![third.id.clone(), second.id.clone()]);
+
+    let page = store.list(&RollQuery { label: Some("Attack".to_string()), limit: 10, offset: 1, ..RollQuery::default() }).unwrap();
+    assert_eq!(page.total, 2);
+    assert_eq!(page.rolls.len(), 1);
+    assert_eq!(page.rolls[0].id, first.id);
+
+    let page = store.list(&RollQuery { after: Some(third.timestamp), limit: 10, ..RollQuery::default() }).unwrap();
+    assert!(page.rolls.iter().all(|r| r.timestamp >= third.timestamp));
+    let page = store.list(&RollQuery { before: Some(first.timestamp), limit: 10, ..RollQuery::default() }).unwrap();
+    assert_eq!(page.total, 0);
+}
+
+#[test]
+fn it_can_store_rolls_in_a_file() {
+    let path = ::std::env::temp_dir().join(format!("roll-api-history-{}.jsonl", ::uuid::Uuid::new_v4()));
+    let mut rng = SeededRng::new(1);
+    let roll = labelled(Some("Stealth"), &mut rng);
+    {
+        let store = FileStore::open(&path).unwrap();
+        store.save(&Record::new(roll.clone())).unwrap();
+    }
+
+    // Reopening the file finds the roll again
+    let store = FileStore::open(&path).unwrap();
+    let found = store.get(&roll.id).unwrap().unwrap().roll;
+    assert_eq!(found.value, roll.value);
+    assert_eq!(found.label, Some("Stealth".to_string()));
+    assert_eq!(found.dice.len(), roll.dice.len());
+    ::std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn it_can_recover_from_an_incomplete_roll() {
+    let path = ::std::env::temp_dir().join(format!("roll-api-incomplete-{}.jsonl", ::uuid::Uuid::new_v4()));
+    let mut rng = SeededRng::new(1);
+    let first = labelled(None, &mut rng);
+    let second = labelled(None, &mut rng);
+    {
+        let store = FileStore::open(&path).unwrap();
+        store.save(&Record::new(first.clone())).unwrap();
+    }
+
+    // The server stopped halfway through writing a roll
+    let line = serde_json::to_string(&Record::new(second.clone())).unwrap();
+    OpenOptions::new().append(true).open(&path).unwrap().write_all(line[..line.len() / 2].as_bytes()).unwrap();
+    {
+        let store = FileStore::open(&path).unwrap();
+        assert!(store.get(&first.id).unwrap().is_some());
+        store.save(&Record::new(second.clone())).unwrap();
+    }
+
+    let store = FileStore::open(&path).unwrap();
+    assert!(store.get(&second.id).unwrap().is_some());
+    assert_eq!(store.list(&RollQuery { limit: 10, ..RollQuery::default() }).unwrap().total, 2);
+
+    // Anything else that can't be read is still an error
+    OpenOptions::new().append(true).open(&path).unwrap().write_all(b"not a roll\n").unwrap();
+    store.save(&Record::new(labelled(None, &mut rng))).unwrap();
+    assert!(FileStore::open(&path).is_err());
+    ::std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn it_can_hide_sealed_rolls() {
+    let path = ::std::env::temp_dir().join(format!("roll-api-sealed-{}.jsonl", ::uuid::Uuid::new_v4()));
+    let mut rng = SeededRng::new(1);
+    let public = labelled(None, &mut rng);
+    let secret = labelled(Some("Perception"), &mut rng);
+    let mut sealed = Record::new(secret.clone());
+    sealed.seal = Some(Seal::new(&secret, None).unwrap());
+    {
+        let store = FileStore::open(&path).unwrap();
+        store.save(&Record::new(public.clone())).unwrap();
+        store.save(&sealed).unwrap();
+    }
+
+    // Sealed rolls are kept across a restart, but left out of the history until they are revealed
+    let store = FileStore::open(&path).unwrap();
+    let found = store.get(&secret.id).unwrap().unwrap();
+    assert!(found.is_hidden(Utc::now()));
+    let page = store.list(&RollQuery { limit: 10, ..RollQuery::default() }).unwrap();
+    assert_eq!(page.total, 1);
+    assert_eq!(page.rolls[0].id, public.id);
+
+    sealed.seal.as_mut().unwrap().reveal();
+    store.save(&sealed).unwrap();
+    let store = FileStore::open(&path).unwrap();
+    let page = store.list(&RollQuery { limit: 10, ..RollQuery::default() }).unwrap();
+    assert_eq!(page.rolls.iter().map(|r| r.id.clone()).collect::<Vec<String>>(), vec![secret.id.clone(), public.id.clone()]);
+    ::std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn it_can_forget_the_oldest_rolls() {
+    let mut rng = SeededRng::new(1);
+    let store = MemoryStore::new(2);
+    let rolls: Vec<Roll> = (0..3).map(|_| labelled(None, &mut rng)).collect();
+    for roll in rolls.iter() {
+        store.save(&Record::new(roll.clone())).unwrap();
+    }
+
+    assert!(store.get(&rolls[0].id).unwrap().is_none());
+    assert_eq!(store.get(&rolls[2].id).unwrap().unwrap().roll.id, rolls[2].id);
+    let page = store.list(&RollQuery { limit: 10, ..RollQuery::default() }).unwrap();
+    assert_eq!(page.total, 2);
+
+    // Saving a roll again replaces it rather than adding it twice
+    let mut relabelled = Record::new(rolls[1].clone());
+    relabelled.roll.label = Some("Initiative".to_string());
+    store.save(&relabelled).unwrap();
+    let page = store.list(&RollQuery { limit: 10, ..RollQuery::default() }).unwrap();
+    assert_eq!(page.total, 2);
+    assert_eq!(page.rolls[1].label, Some("Initiative".to_string()));
+}
+
+#[test]
+fn it_can_list_rolls_for_members_of_a_room() {
+    let mut rng = SeededRng::new(1);
+    let store = MemoryStore::new(100);
+    let member = |name: &str, is_gm| Member { is_gm, name: name.to_string(), token: String::new() };
+    let viewer = |name: &str, is_gm| Viewer { member: member(name, is_gm), room_id: "tomb".to_string() };
+    let in_room = |visibility, recipients: Vec<&str>, rng: &mut SeededRng| {
+        let mut record = Record::new(labelled(None, rng));
+        record.audience = Some(Audience {
+            member: "Alice".to_string(),
+            recipients: recipients.iter().map(|name| name.to_string()).collect(),
+            room_id: "tomb".to_string(),
+            visibility,
+        });
+        record
+    };
+
+    let outside = Record::new(labelled(None, &mut rng));
+    let public = in_room(Visibility::Public, vec![], &mut rng);
+    let secret = in_room(Visibility::Gm, vec![], &mut rng);
+    let whisper = in_room(Visibility::Whisper, vec!["Bob"], &mut rng);
+    for record in [&outside, &public, &secret, &whisper].iter() {
+        store.save(record).unwrap();
+    }
+
+    let ids = |viewer: Option<Viewer>| -> Vec<String> {
+        let page = store.list(&RollQuery { limit: 10, viewer, ..RollQuery::default() }).unwrap();
+        page.rolls.into_iter().map(|roll| roll.id).collect()
+    };
+    assert_eq!(ids(None), vec![public.roll.id.clone(), outside.roll.id.clone()]);
+    assert_eq!(ids(Some(viewer("Dungeon Master", true))), vec![secret.roll.id.clone(), public.roll.id.clone()]);
+    assert_eq!(ids(Some(viewer("Bob", false))), vec![whisper.roll.id.clone(), public.roll.id.clone()]);
+    assert_eq!(ids(Some(viewer("Alice", false))).len(), 3);
+
+    // Secret rolls are recorded, but can't be looked up by anyone outside the room
+    let found = store.get(&secret.roll.id).unwrap().unwrap();
+    assert!(!found.is_visible_to(None, Utc::now()));
+    assert!(found.is_visible_to(Some(&viewer("Dungeon Master", true)), Utc::now()));
+}
@@ -0,0 +1,390 @@
+use die::DieType;
+use error::RollError;
+use rng::DiceRng;
+#[cfg(test)]
+use rng::{FixedRng, SeededRng};
+use roll::{Roll, RollFlags};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// How many of each symbol a face shows (e.g. { "advantage": 1, "success": 1 }), blank faces are empty
+pub type Face = BTreeMap<String, u16>;
+
+/// A die whose faces show symbols instead of numbers (e.g. the Genesys ability die)
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct SymbolicDie {
+    /// Name used in commands (e.g. "Ability" in 2dAbility)
+    pub name: String,
+
+    /// Pairs of symbols that cancel each other out one for one when this die is rolled
+    #[serde(default)]
+    pub cancellations: Vec<(String, String)>,
+
+    pub faces: Vec<Face>,
+}
+
+impl SymbolicDie {
+    /// A die from a list of faces, each one the symbols it shows (e.g. ["success", "advantage"])
+    pub fn new(name: &str, faces: &[&[&str]]) -> SymbolicDie {
+        let faces = faces.iter().map(|symbols| {
+            let mut face = Face::new();
+            for symbol in symbols.iter() {
+                *face.entry(symbol.to_string()).or_insert(0) += 1;
+            }
+            face
+        }).collect();
+        SymbolicDie {
+            name: name.to_string(),
+            cancellations: vec![],
+            faces,
+        }
+    }
+}
+
+fn is_name(name: &str) -> bool {
+    name.chars().next().map(|c| c.is_ascii_alphabetic()).unwrap_or(false)
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Read a group of symbolic dice (e.g. "2dAbility"), returning the number of dice and the name
+fn read_group(group: &str) -> Option<(i16, &str)> {
+    let d = group.find('d')?;
+    let (count, name) = (&group[..d], &group[d + 1..]);
+    if !count.chars().all(|c| c.is_digit(10)) || !is_name(name) || name == "F" {
+        return None;
+    }
+    let n = if count.is_empty() { 1 } else { count.parse().ok()? };
+    Some((n, name))
+}
+
+/// Split a command into its comment and its groups, each with its offset in the command
+fn split(command: &str) -> Result<(String, Vec<(usize, String)>), RollError> {
+    let mut comments = vec![];
+    let mut rest = String::new();
+    let mut offsets = vec![];
+    let mut chars = command.char_indices();
+    while let Some((offset, c)) = chars.next() {
+        if c == '[' {
+            let comment: String = chars.by_ref().map(|(_, c)| c).take_while(|c| *c != ']').collect();
+            if !command[offset..].contains(']') {
+                return Err(RollError::Parse { offset, message: "Expected \"]\"".to_string() });
+            }
+            comments.push(comment);
+        } else {
+            rest.push(c);
+            offsets.push(offset);
+        }
+    }
+
+    let mut groups = vec![];
+    let mut start = 0;
+    for part in rest.split('+') {
+        let leading = part.find(|c: char| !c.is_whitespace()).unwrap_or(part.len());
+        let offset = offsets.get(start + leading).cloned().unwrap_or(command.len());
+        groups.push((offset, part.trim().to_string()));
+        start += part.len() + 1;
+    }
+    Ok((comments.join(" "), groups))
+}
+
+/// If a command rolls symbolic dice (e.g. "2dAbility+1dProficiency") rather than numbers
+pub fn is_symbolic(command: &str) -> bool {
+    match split(command) {
+        Ok((_, groups)) => groups.iter().any(|&(_, ref group)| read_group(group).is_some()),
+        Err(_) => false,
+    }
+}
+
+/// Symbolic dice that can be used in commands, by name
+#[derive(Clone, Debug)]
+pub struct DiceRegistry {
+    /// Pairs of symbols that cancel each other out one for one (e.g. success and failure)
+    pub cancellations: Vec<(String, String)>,
+
+    /// Names of the dice the registry was created with, which can't be replaced or removed
+    builtin: BTreeSet<String>,
+
+    dice: BTreeMap<String, SymbolicDie>,
+}
+
+impl DiceRegistry {
+    pub fn new() -> DiceRegistry {
+        DiceRegistry {
+            cancellations: vec![],
+            builtin: BTreeSet::new(),
+            dice: BTreeMap::new(),
+        }
+    }
+
+    /// The narrative dice of Genesys and Star Wars (FFG)
+    ///
+    /// A triumph also counts as a success and a despair as a failure. Successes cancel failures
+    /// and advantages cancel threats, the Force die's light and dark side points are never cancelled.
+    pub fn genesys() -> DiceRegistry {
+        let mut registry = DiceRegistry::new();
+        registry.cancellations = vec![
+            ("success".to_string(), "failure".to_string()),
+            ("advantage".to_string(), "threat".to_string()),
+        ];
+
+        let dice = vec![
+            SymbolicDie::new("Boost", &[
+                &[], &[], &["success"], &["success", "advantage"], &["advantage", "advantage"], &["advantage"],
+            ]),
+            SymbolicDie::new("Setback", &[
+                &[], &[], &["failure"], &["failure"], &["threat"], &["threat"],
+            ]),
+            SymbolicDie::new("Ability", &[
+                &[], &["success"], &["success"], &["success", "success"], &["advantage"], &["advantage"],
+                &["success", "advantage"], &["advantage", "advantage"],
+            ]),
+            SymbolicDie::new("Difficulty", &[
+                &[], &["failure"], &["failure", "failure"], &["threat"], &["threat"], &["threat"],
+                &["threat", "threat"], &["failure", "threat"],
+            ]),
+            SymbolicDie::new("Proficiency", &[
+                &[], &["success"], &["success"], &["success", "success"], &["success", "success"], &["advantage"],
+                &["success", "advantage"], &["success", "advantage"], &["success", "advantage"],
+                &["advantage", "advantage"], &["advantage", "advantage"], &["triumph", "success"],
+            ]),
+            SymbolicDie::new("Challenge", &[
+                &[], &["failure"], &["failure"], &["failure", "failure"], &["failure", "failure"], &["threat"],
+                &["threat"], &["failure", "threat"], &["failure", "threat"], &["threat", "threat"],
+                &["threat", "threat"], &["despair", "failure"],
+            ]),
+            SymbolicDie::new("Force", &[
+                &["dark"], &["dark"], &["dark"], &["dark"], &["dark"], &["dark"], &["dark", "dark"],
+                &["light"], &["light"], &["light", "light"], &["light", "light"], &["light", "light"],
+            ]),
+        ];
+        for die in dice.into_iter() {
+            registry.register(die).unwrap();
+        }
+        registry.builtin = registry.dice.keys().cloned().collect();
+        registry
+    }
+
+    /// If a die came with the registry, so can't be replaced or removed
+    pub fn is_builtin(&self, name: &str) -> bool {
+        self.builtin.contains(name)
+    }
+
+    fn builtin_error(name: &str) -> RollError {
+        RollError::UnsupportedArgument {
+            offset: 0,
+            message: format!("\"{}\" is a built-in die and can't be changed", name),
+        }
+    }
+
+    /// Add a die, replacing any die with the same name unless it is built in
+    pub fn register(&mut self, die: SymbolicDie) -> Result<(), RollError> {
+        if self.is_builtin(&die.name) {
+            return Err(DiceRegistry::builtin_error(&die.name));
+        }
+        if !is_name(&die.name) || die.name == "F" {
+            return Err(RollError::Parse {
+                offset: 0,
+                message: format!("\"{}\" is not a valid die name, start with a letter and use letters, digits and _", die.name),
+            });
+        }
+        if die.faces.is_empty() || die.faces.len() > i16::max_value() as usize {
+            return Err(RollError::InvalidRange {
+                offset: 0,
+                message: format!("A die needs between 1 and {} faces", i16::max_value()),
+            });
+        }
+        for &(ref symbol, ref opposite) in die.cancellations.iter() {
+            if symbol.is_empty() || symbol == opposite {
+                return Err(RollError::InvalidRange {
+                    offset: 0,
+                    message: format!("\"{}\" can only cancel a different symbol", symbol),
+                });
+            }
+        }
+        self.dice.insert(die.name.clone(), die);
+        Ok(())
+    }
+
+    pub fn get(&self, name: &str) -> Option<&SymbolicDie> {
+        self.dice.get(name)
+    }
+
+    /// Remove a die, returning `None` if there was no die with the name
+    pub fn remove(&mut self, name: &str) -> Result<Option<SymbolicDie>, RollError> {
+        if self.is_builtin(name) {
+            return Err(DiceRegistry::builtin_error(name));
+        }
+        Ok(self.dice.remove(name))
+    }
+
+    /// Every registered die, by name
+    pub fn dice(&self) -> Vec<&SymbolicDie> {
+        self.dice.values().collect()
+    }
+
+    /// Total every symbol shown, then cancel out opposing symbols, leaving only symbols that remain
+    pub fn cancel(&self, faces: &[&Face]) -> BTreeMap<String, i32> {
+        self.cancel_with(faces, &[])
+    }
+
+    /// Like `cancel`, also cancelling the pairs of symbols of the named dice
+    fn cancel_with(&self, faces: &[&Face], names: &[String]) -> BTreeMap<String, i32> {
+        let mut cancellations: Vec<&(String, String)> = self.cancellations.iter().collect();
+        for name in names.iter() {
+            if let Some(die) = self.get(name) {
+                cancellations.extend(die.cancellations.iter());
+            }
+        }
+
+        let mut totals: BTreeMap<String, i32> = BTreeMap::new();
+        for face in faces.iter() {
+            for (symbol, count) in face.iter() {
+                *totals.entry(symbol.clone()).or_insert(0) += *count as i32;
+            }
+        }
+        for &&(ref symbol, ref opposite) in cancellations.iter() {
+            let cancelled = *totals.get(symbol).unwrap_or(&0).min(totals.get(opposite).unwrap_or(&0));
+            for name in [symbol, opposite].iter() {
+                if let Some(total) = totals.get_mut(*name) {
+                    *total -= cancelled;
+                }
+            }
+        }
+        totals.into_iter().filter(|&(_, total)| total > 0).collect()
+    }
+
+    /// Roll a command of symbolic dice added together (e.g. "2dAbility+1dDifficulty [Stealth]")
+    ///
+    /// Each group is rolled like a numbered die with one side per face, so `Die.value` is the face
+    /// number starting at 1. The roll's `symbols` are the totals once opposing symbols cancel out,
+    /// and its value is 0.
+    pub fn roll<R: DiceRng + ?Sized>(&self, command: &str, rng: &mut R) -> Result<Roll, RollError> {
+        let (comment, groups) = split(command)?;
+        let mut names = vec![];
+        let mut rolls = vec![];
+        for (offset, group) in groups.into_iter() {
+            let (n, name) = read_group(&group).ok_or_else(|| RollError::UnsupportedArgument {
+                offset,
+                message: format!("\"{}\" can't be rolled with symbolic dice, only dice like 2dAbility can be added together", group),
+            })?;
+            let die = self.get(name).ok_or_else(|| RollError::UnsupportedArgument {
+                offset,
+                message: format!("Unknown die \"d{}\"", name),
+            })?;
+            if n > ::roll::MAX_DICE {
+                return Err(RollError::LimitExceeded {
+                    offset,
+                    message: format!("Can't roll more than {} dice at once", ::roll::MAX_DICE),
+                });
+            }
+
+            let mut flags = RollFlags::new();
+            flags.n = n;
+            flags.die = DieType::Symbolic;
+            flags.min = 1;
+            flags.max = die.faces.len() as i16;
+            flags.equation = format!("{}d{}", n, name);
+            let mut roll = Roll::new(flags, rng)?;
+            for rolled in roll.dice.iter_mut() {
+                rolled.symbols = Some(die.faces[(rolled.value - 1) as usize].clone());
+            }
+            names.push(name.to_string());
+            roll.symbols = Some(self.totals(&roll, &names[names.len() - 1..]));
+            roll.raw_value = 0;
+            roll.value = 0;
+            rolls.push(roll);
+        }
+
+        let mut roll = Roll::combine(rolls);
+        roll.symbols = Some(self.totals(&roll, &names));
+        if !comment.is_empty() {
+            roll.comment = comment;
+        }
+        Ok(roll)
+    }
+
+    fn totals(&self, roll: &Roll, names: &[String]) -> BTreeMap<String, i32> {
+        let faces: Vec<&Face> = roll.dice.iter().filter_map(|d| d.symbols.as_ref()).collect();
+        self.cancel_with(&faces, names)
+    }
+}
+
+#[test]
+fn it_can_cancel_symbols() {
+    let registry = DiceRegistry::genesys();
+    let ability = registry.get("Ability").unwrap();
+    let challenge = registry.get("Challenge").unwrap();
+
+    // Two successes and an advantage against a despair, which is also a failure
+    let totals = registry.cancel(&[&ability.faces[3], &ability.faces[4], &challenge.faces[11]]);
+    let mut expected = BTreeMap::new();
+    expected.insert("advantage".to_string(), 1);
+    expected.insert("despair".to_string(), 1);
+    expected.insert("success".to_string(), 1);
+    assert_eq!(totals, expected);
+}
+
+#[test]
+fn it_can_roll_symbolic_dice() {
+    assert!(is_symbolic("2dAbility+1dProficiency"));
+    assert!(is_symbolic("dBoost [Stealth]"));
+    assert!(!is_symbolic("4dF"));
+    assert!(!is_symbolic("1d20+5"));
+
+    let registry = DiceRegistry::genesys();
+    let roll = registry.roll("2dAbility + 1dDifficulty [Stealth]", &mut FixedRng::new(vec![4, 2, 3])).unwrap();
+    assert_eq!(roll.rolls.len(), 2);
+    assert_eq!(roll.dice.len(), 3);
+    assert_eq!(roll.comment, "Stealth");
+    assert_eq!(roll.value, 0);
+
+    // Two successes and a success against two failures leave a success
+    let mut expected = BTreeMap::new();
+    expected.insert("success".to_string(), 1);
+    assert_eq!(roll.symbols, Some(expected));
+    assert!(roll.dice.iter().all(|d| d.symbols.is_some() && !d.is_critical));
+
+    let error = registry.roll("2dAbility+1dBogus", &mut SeededRng::new(1)).unwrap_err();
+    assert_eq!(error.offset(), 10);
+    assert!(registry.roll("2dAbility+1d20", &mut SeededRng::new(1)).is_err());
+
+    let mut registry = DiceRegistry::new();
+    assert!(registry.register(SymbolicDie::new("Empty", &[])).is_err());
+    assert!(registry.register(SymbolicDie::new("2d", &[&["hit"]])).is_err());
+    registry.register(SymbolicDie::new("Hit", &[&["hit"], &[]])).unwrap();
+    assert_eq!(registry.roll("3dHit", &mut FixedRng::new(vec![1])).unwrap().symbols.unwrap()["hit"], 3);
+}
+
+#[test]
+fn it_can_protect_builtin_dice() {
+    let mut registry = DiceRegistry::genesys();
+    assert!(registry.is_builtin("Ability"));
+    assert!(registry.register(SymbolicDie::new("Ability", &[&["success"]])).is_err());
+    assert!(registry.remove("Ability").is_err());
+    assert_eq!(registry.get("Ability").unwrap().faces.len(), 8);
+
+    registry.register(SymbolicDie::new("Hit", &[&["hit"]])).unwrap();
+    assert!(!registry.is_builtin("Hit"));
+    assert!(registry.remove("Hit").unwrap().is_some());
+    assert!(registry.remove("Hit").unwrap().is_none());
+}
+
+#[test]
+fn it_can_cancel_symbols_of_registered_dice() {
+    let mut registry = DiceRegistry::new();
+    let mut attack = SymbolicDie::new("Attack", &[&["hit", "hit"]]);
+    attack.cancellations = vec![("hit".to_string(), "miss".to_string())];
+    registry.register(attack).unwrap();
+    registry.register(SymbolicDie::new("Defend", &[&["miss"]])).unwrap();
+
+    let roll = registry.roll("1dAttack+1dDefend", &mut FixedRng::new(vec![1])).unwrap();
+    let mut expected = BTreeMap::new();
+    expected.insert("hit".to_string(), 1);
+    assert_eq!(roll.symbols, Some(expected));
+
+    // Only rolls with the die cancel its symbols
+    assert_eq!(registry.roll("1dDefend", &mut FixedRng::new(vec![1])).unwrap().symbols.unwrap()["miss"], 1);
+
+    let mut invalid = SymbolicDie::new("Invalid", &[&["hit"]]);
+    invalid.cancellations = vec![("hit".to_string(), "hit".to_string())];
+    assert!(registry.register(invalid).is_err());
+}
@@ -1,14 +1,101 @@
-use die::*;
+use chrono::DateTime;
+use chrono::prelude::Utc;
+use config::Config;
+use rand;
+use rocket::{Outcome, Request, State};
+use rocket::http::Status;
+use rocket::request::{self, FromRequest};
+use rocket::response::status;
 use rocket_contrib::{Json, Value};
-use roll::*;
-use std::time::Instant;
-use ttml::arg::{Arg, ArgValue, ComparisonArg, RollArg};
-use ttml::parser::parse_step_p;
+use roll_api;
+use roll_api::character::{self, Character, Substitution};
+use roll_api::command;
+use roll_api::distribution::{self, Distribution};
+use roll_api::error::RollError;
+use roll_api::expression::Rounding;
+use roll_api::fair::{Commitment, Proof};
+use roll_api::macros::Macro;
+use roll_api::receipt::{self, Receipt};
+use roll_api::rng::{DiceRng, SeededRng, SystemRng};
+use roll_api::roll::*;
+use roll_api::room::{Audience, Member, Room, RoomEvent, Visibility};
+use roll_api::seal::Seal;
+use roll_api::simulation::{self, Simulation};
+use roll_api::storage::{Record, RollQuery, RollStore, Viewer};
+use roll_api::symbolic::{self, DiceRegistry, SymbolicDie};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Character sheets whose attributes can be used in commands, by id
+pub type Characters = Mutex<HashMap<String, Character>>;
+
+/// Symbolic dice that can be used in commands (e.g. "2dAbility+1dProficiency"), by name
+pub type Dice = Mutex<DiceRegistry>;
+
+/// Commitments to server seeds, by id
+pub type Commitments = Mutex<HashMap<String, Commitment>>;
+
+/// History of every roll, so a roll can be looked up by its id
+pub type Rolls = Box<RollStore>;
+
+/// Saved macros, by name
+pub type Macros = Mutex<HashMap<String, Macro>>;
+
+/// Rooms players roll in together, by id, shared with the room event server
+pub type Rooms = Arc<Mutex<HashMap<String, Room>>>;
 
 #[derive(Serialize)]
 pub struct RollsResponse {
     pub roll: Roll,
     pub execution_time: u64,
+
+    /// Seed used to roll the dice, rolling the same command with it gives the same result
+    pub seed: Option<u64>,
+
+    /// How to check the roll once the server seed of its commitment is revealed
+    pub proof: Option<Proof>,
+
+    /// Attributes of the character that were substituted into the command
+    pub substitutions: Option<Vec<Substitution>>,
+}
+
+#[derive(Serialize)]
+pub struct DistributionResponse {
+    pub distribution: Distribution,
+    pub execution_time: u64,
+}
+
+#[derive(Serialize)]
+pub struct SimulationResponse {
+    pub simulation: Simulation,
+    pub execution_time: u64,
+
+    /// Seed the trials were rolled with, simulating again with it gives the same results
+    pub seed: u64,
+}
+
+#[derive(Serialize)]
+pub struct CommitmentResponse {
+    pub id: String,
+    pub commitment: String,
+    pub is_revealed: bool,
+    pub nonce: u64,
+
+    /// Only included once the commitment is revealed
+    pub server_seed: Option<String>,
+}
+
+impl CommitmentResponse {
+    fn new(commitment: &Commitment) -> CommitmentResponse {
+        CommitmentResponse {
+            id: commitment.id.clone(),
+            commitment: commitment.commitment.clone(),
+            is_revealed: commitment.is_revealed,
+            nonce: commitment.nonce,
+            server_seed: if commitment.is_revealed { Some(commitment.server_seed.clone()) } else { None },
+        }
+    }
 }
 
 #[error(404)]
@@ -19,185 +106,1053 @@ fn not_found() -> Json<Value> {
     }))
 }
 
-#[get("/<command>", format = "application/json")]
-pub fn roll(command: String) -> Option<Json<RollsResponse>> {
+/// A command that could not be rolled, with the reason why
+pub type ErrorResponse = status::Custom<Json<Value>>;
+
+/// Respond with an error, a 400 unless the server is at fault
+fn error_response(error: RollError) -> ErrorResponse {
+    let status = match error {
+        RollError::Random { .. } | RollError::Storage { .. } => Status::InternalServerError,
+        _ => Status::BadRequest,
+    };
+    status::Custom(status, Json(json!({
+        "status": "error",
+        "reason": error.to_string(),
+        "error": error,
+    })))
+}
+
+/// Refuse a request that doesn't have the right secret
+fn forbidden(reason: &str) -> ErrorResponse {
+    status::Custom(Status::Forbidden, Json(json!({
+        "status": "error",
+        "reason": reason,
+    })))
+}
+
+/// Read the rounding option, rounding down when there is none
+fn rounding(name: &Option<String>) -> Result<Rounding, RollError> {
+    match name {
+        &Some(ref name) => Rounding::from_name(name).ok_or_else(|| RollError::UnsupportedArgument {
+            offset: 0,
+            message: format!("Unknown rounding \"{}\", use floor, ceil, round or truncate", name),
+        }),
+        &None => Ok(Rounding::default()),
+    }
+}
+
+#[derive(Default, FromForm)]
+pub struct RollOptions {
+    /// Character whose attributes are used in the command (e.g. "1d20+@str_mod")
+    pub character: Option<String>,
+
+    /// How divisions are rounded: floor (default), ceil, round or truncate
+    pub rounding: Option<String>,
+
+    /// Seed for reproducible rolls, otherwise the operating system's random numbers are used
+    pub seed: Option<u64>,
+}
+
+/// Sign a roll so it can be verified later
+fn sign(roll: &mut Roll, config: &Config) {
+    roll.signature = Some(receipt::sign(roll, config.signing_key.as_bytes()));
+}
+
+/// Sign a roll and record it in the history
+fn record(roll: &mut Roll, config: &Config, rolls: &Rolls) -> Result<(), RollError> {
+    sign(roll, config);
+    rolls.save(&Record::new(roll.clone())).map_err(|e| RollError::Storage { offset: 0, message: e.to_string() })
+}
+
+// Commands are ranked after fixed paths like /rolls so they aren't rolled as a command
+#[get("/<command>", format = "application/json", rank = 4)]
+pub fn roll(command: String, characters: State<Characters>, config: State<Config>, dice: State<Dice>, rolls: State<Rolls>) -> Result<Json<RollsResponse>, ErrorResponse> {
+    roll_with_options(command, RollOptions::default(), characters, config, dice, rolls)
+}
+
+#[get("/<command>?<options>", format = "application/json", rank = 3)]
+pub fn roll_with_options(command: String, options: RollOptions, characters: State<Characters>, config: State<Config>, dice: State<Dice>, rolls: State<Rolls>) -> Result<Json<RollsResponse>, ErrorResponse> {
+    // Start the timer
+    let start = Instant::now();
+
+    let rounding = rounding(&options.rounding).map_err(error_response)?;
+    let (command, substitutions) = resolve_attributes(&command, &options.character, &characters).map_err(error_response)?;
+
+    // Parse the command into groups of dice and roll them
+    let mut roll = roll_command(&command, rounding, options.seed, &dice)
+        .map_err(|error| unresolve(error, &substitutions))
+        .map_err(error_response)?;
+    record(&mut roll, &config, &rolls).map_err(error_response)?;
+
+    let elapsed = start.elapsed();
+    let response = RollsResponse {
+        roll,
+        seed: options.seed,
+        proof: None,
+        substitutions,
+        execution_time: (elapsed.as_secs() * 1000) + (elapsed.subsec_nanos() / 1000000) as u64,
+    };
+    Ok(Json(response))
+}
+
+/// Substitute the attributes of a character into a command
+///
+/// Commands are only resolved when they are rolled for a character, otherwise `@` is left alone.
+fn resolve_attributes(command: &str, character: &Option<String>, characters: &Characters) -> Result<(String, Option<Vec<Substitution>>), RollError> {
+    match character {
+        &Some(ref id) => {
+            let characters = characters.lock().unwrap();
+            let character = characters.get(id).ok_or_else(|| RollError::InvalidRange {
+                offset: 0,
+                message: format!("Unknown character \"{}\"", id),
+            })?;
+            let (command, substitutions) = character::resolve(command, &character.attributes)?;
+            Ok((command, Some(substitutions)))
+        },
+        &None => Ok((command.to_string(), None)),
+    }
+}
+
+/// Point an error in a resolved command back at the command that was sent
+fn unresolve(error: RollError, substitutions: &Option<Vec<Substitution>>) -> RollError {
+    match substitutions {
+        &Some(ref substitutions) => error.map_offset(|offset| character::original_offset(offset, substitutions)),
+        &None => error,
+    }
+}
+
+/// Roll a command with a seed, or with the operating system's random numbers when there is none
+///
+/// The seed is kept on the roll, so its signature shows the client could have chosen the dice.
+fn roll_command(command: &str, rounding: Rounding, seed: Option<u64>, dice: &Dice) -> Result<Roll, RollError> {
+    match seed {
+        Some(seed) => roll_with(command, rounding, dice, &mut SeededRng::new(seed)).map(|mut roll| {
+            roll.seed = Some(seed);
+            roll
+        }),
+        None => match SystemRng::new() {
+            Ok(mut rng) => roll_with(command, rounding, dice, &mut rng),
+            Err(e) => Err(RollError::Random { offset: 0, message: e.to_string() }),
+        },
+    }
+}
+
+/// Roll a command, using the registered dice when it rolls symbolic dice (e.g. "2dAbility")
+fn roll_with<R: DiceRng>(command: &str, rounding: Rounding, dice: &Dice, rng: &mut R) -> Result<Roll, RollError> {
+    if symbolic::is_symbolic(command) {
+        dice.lock().unwrap().roll(command, rng)
+    } else {
+        roll_api::roll_with(command, rounding, rng)
+    }
+}
+
+#[derive(Deserialize)]
+pub struct RollRequest {
+    /// Command to roll, any characters are allowed (e.g. "1d20+5 [Attack #2 / sword]")
+    pub command: String,
+
+    /// Character whose attributes are used in the command (e.g. "1d20+@str_mod")
+    pub character: Option<String>,
+
+    /// Comment for the roll, added after any comments in the command
+    pub comment: Option<String>,
+
+    /// Name for the roll, returned as `roll.label`
+    pub label: Option<String>,
+
+    /// How divisions are rounded: floor (default), ceil, round or truncate
+    pub rounding: Option<String>,
+
+    /// Seed for reproducible rolls, otherwise the operating system's random numbers are used
+    pub seed: Option<u64>,
+}
+
+/// Roll the command in a request body, adding its comment and label, and record it
+///
+/// Without a history the roll is only signed, e.g. for secret rolls that shouldn't be listed.
+fn roll_request(request: RollRequest, characters: &Characters, config: &Config, dice: &Dice, rolls: Option<&Rolls>) -> Result<RollsResponse, RollError> {
+    // Start the timer
+    let start = Instant::now();
+
+    let rounding = rounding(&request.rounding)?;
+    let (command, substitutions) = resolve_attributes(&request.command, &request.character, characters)?;
+    let mut roll = roll_command(&command, rounding, request.seed, dice).map_err(|error| unresolve(error, &substitutions))?;
+
+    if let Some(comment) = request.comment {
+        roll.comment = if roll.comment.is_empty() { comment } else { roll.comment + " " + &comment };
+    }
+    roll.label = request.label;
+    match rolls {
+        Some(rolls) => record(&mut roll, config, rolls)?,
+        None => sign(&mut roll, config),
+    }
+
+    let elapsed = start.elapsed();
+    Ok(RollsResponse {
+        roll,
+        seed: request.seed,
+        proof: None,
+        substitutions,
+        execution_time: (elapsed.as_secs() * 1000) + (elapsed.subsec_nanos() / 1000000) as u64,
+    })
+}
+
+#[post("/roll", format = "application/json", data = "<request>")]
+pub fn roll_with_body(request: Json<RollRequest>, characters: State<Characters>, config: State<Config>, dice: State<Dice>, rolls: State<Rolls>) -> Result<Json<RollsResponse>, ErrorResponse> {
+    roll_request(request.into_inner(), &characters, &config, &dice, Some(&*rolls)).map(Json).map_err(error_response)
+}
+
+#[derive(Deserialize)]
+pub struct BatchRequest {
+    /// Chosen by the client to match each result to its request (e.g. "goblin-3")
+    pub key: String,
+
+    #[serde(flatten)]
+    pub request: RollRequest,
+}
+
+#[derive(Serialize)]
+pub struct BatchResult {
+    pub key: String,
+
+    /// Why the command could not be rolled, the rest of the batch is still rolled
+    pub error: Option<RollError>,
+
+    pub result: Option<RollsResponse>,
+}
+
+#[derive(Serialize)]
+pub struct BatchResponse {
+    pub rolls: Vec<BatchResult>,
+    pub execution_time: u64,
+}
+
+#[post("/rolls/batch", format = "application/json", data = "<requests>")]
+pub fn roll_batch(requests: Json<Vec<BatchRequest>>, characters: State<Characters>, config: State<Config>, dice: State<Dice>, rolls: State<Rolls>) -> Result<Json<BatchResponse>, ErrorResponse> {
     // Start the timer
     let start = Instant::now();
 
-    // Parse the roll command as if we're passing it through TTML
-    let input = "!roll ".to_string() + &command;
-    let step_result = parse_step_p(input.as_bytes());
-
-    // Successful parse, roll the die
-    if step_result.is_err() == false {
-        let (_, step) = step_result.unwrap();
-
-        // Build a list of dice to roll
-        let mut rolls: Vec<Roll> = vec![];
-
-        // Build a list of flags
-        let mut flags = RollFlags::new();
-
-        // Loop through each step, push the dice when necessary
-        for arg in &step.args {
-            if let &Arg::Roll(RollArg::N(ArgValue::Number(n))) = arg {
-                flags.n = n as i16;
-                flags.equation = flags.equation + &n.to_string();
-            } else if let &Arg::Roll(RollArg::D(ArgValue::Number(d))) = arg {
-                flags.max = d as i16;
-                flags.die = match d {
-                    100   => DieType::D100,
-                    20    => DieType::D20,
-                    12    => DieType::D12,
-                    10    => DieType::D10,
-                    8     => DieType::D8,
-                    6     => DieType::D6,
-                    4     => DieType::D4,
-                    _     => DieType::Other,
-                };
-                flags.equation = flags.equation + &"d" + &d.to_string();
-            } else if let &Arg::Roll(RollArg::H(ArgValue::Number(h))) = arg {
-                flags.kh = h as i16;
-                flags.equation = flags.equation + &"kh" + &h.to_string();
-            } else if let &Arg::Roll(RollArg::L(ArgValue::Number(l))) = arg {
-                flags.kl = l as i16;
-                flags.equation = flags.equation + &"kl" + &l.to_string();
-            } else if let &Arg::Roll(RollArg::GT(ArgValue::Number(gt))) = arg {
-                flags.gt = gt as u16;
-                flags.equation = flags.equation + &"gt" + &gt.to_string();
-            } else if let &Arg::Roll(RollArg::GTE(ArgValue::Number(gte))) = arg {
-                flags.gte = gte as u16;
-                flags.equation = flags.equation + &"gte" + &gte.to_string();
-            } else if let &Arg::Roll(RollArg::LT(ArgValue::Number(lt))) = arg {
-                flags.lt = lt as u16;
-                flags.equation = flags.equation + &"lt" + &lt.to_string();
-            } else if let &Arg::Roll(RollArg::LTE(ArgValue::Number(lte))) = arg {
-                flags.lte = lte as u16;
-                flags.equation = flags.equation + &"lte" + &lte.to_string();
-            } else if let &Arg::Roll(RollArg::RR(ref comparitive)) = arg {
-                flags.rr = match &comparitive.value {
-                    &ArgValue::Number(n) => n as i16,
-                    _ => 0
-                };
-                match comparitive.op {
-                    ComparisonArg::GreaterThan => {
-                        flags.rr_op = Some(ComparisonArg::GreaterThan);
-                        flags.equation = flags.equation + &"rr>" + &flags.rr.to_string();
-                    },
-                    ComparisonArg::GreaterThanOrEqual => {
-                        flags.rr_op = Some(ComparisonArg::GreaterThanOrEqual);
-                        flags.equation = flags.equation + &"rr>=" + &flags.rr.to_string();
-                    },
-                    ComparisonArg::LessThan => {
-                        flags.rr_op = Some(ComparisonArg::LessThan);
-                        flags.equation = flags.equation + &"rr<" + &flags.rr.to_string();
-                    },
-                    ComparisonArg::LessThanOrEqual => {
-                        flags.rr_op = Some(ComparisonArg::LessThanOrEqual);
-                        flags.equation = flags.equation + &"rr<=" + &flags.rr.to_string();
-                    },
-                    ComparisonArg::EqualTo => {
-                        flags.rr_op = Some(ComparisonArg::EqualTo);
-                        flags.equation = flags.equation + &"rr==" + &flags.rr.to_string();
-                    },
-                };
-            } else if let &Arg::Roll(RollArg::RO(ref comparitive)) = arg {
-                flags.ro = match &comparitive.value {
-                    &ArgValue::Number(n) => n as i16,
-                    _ => 0
-                };
-                match comparitive.op {
-                    ComparisonArg::GreaterThan => {
-                        flags.ro_op = Some(ComparisonArg::GreaterThan);
-                        flags.equation = flags.equation + &"ro>" + &flags.ro.to_string();
-                    },
-                    ComparisonArg::GreaterThanOrEqual => {
-                        flags.ro_op = Some(ComparisonArg::GreaterThanOrEqual);
-                        flags.equation = flags.equation + &"ro>=" + &flags.ro.to_string();
-                    },
-                    ComparisonArg::LessThan => {
-                        flags.ro_op = Some(ComparisonArg::LessThan);
-                        flags.equation = flags.equation + &"ro<" + &flags.ro.to_string();
-                    },
-                    ComparisonArg::LessThanOrEqual => {
-                        flags.ro_op = Some(ComparisonArg::LessThanOrEqual);
-                        flags.equation = flags.equation + &"ro<=" + &flags.ro.to_string();
-                    },
-                    ComparisonArg::EqualTo => {
-                        flags.ro_op = Some(ComparisonArg::EqualTo);
-                        flags.equation = flags.equation + &"ro==" + &flags.ro.to_string();
-                    },
-                };
-            } else if let &Arg::Roll(RollArg::ModifierPos(ArgValue::Number(mp))) = arg {
-                if mp != 0 {
-                    flags.modifiers.push(mp as i16);
-                    flags.equation = flags.equation + &"+" + &mp.to_string();
-                }
-            } else if let &Arg::Roll(RollArg::ModifierNeg(ArgValue::Number(mn))) = arg {
-                if mn != 0 {
-                    flags.modifiers.push(mn as i16 * -1);
-                    flags.equation = flags.equation + &"-" + &mn.to_string();
-                }
-            } else if let &Arg::Roll(RollArg::Max(ArgValue::Number(max))) = arg {
-                flags.max = max as i16;
-                flags.equation = flags.equation + &"max" + &max.to_string();
-            } else if let &Arg::Roll(RollArg::Min(ArgValue::Number(min))) = arg {
-                flags.min = min as i16;
-                flags.equation = flags.equation + &"min" + &min.to_string();
-            } else if let &Arg::Roll(RollArg::Sides(ref r_sides)) = arg {
-                let mut min = 0;
-                let mut max = 0;
-                let sides: Vec<i16> = r_sides.into_iter().map(|side| (
-                    match side {
-                        &ArgValue::Number(n) => {
-                            if n < min || n == 0 {
-                                min = n.clone()
-                            } else if n > max {
-                                max = n.clone()
-                            }
-                            n.clone() as i16
-                        },
-                        _ => 0 as i16
-                    }
-                )).collect();
-                flags.sides = Some(sides.clone());
-                flags.min = min as i16;
-                flags.max = max as i16;
-                let side_strs: Vec<String> = sides.into_iter().map(|side| (side.to_string())).collect();
-                flags.equation = flags.equation + &"[" + &side_strs.join(",") + &"]";
-            } else if let &Arg::Roll(RollArg::Comment(ArgValue::Text(ref comment))) = arg {
-                flags.comment = comment.to_string();
-                flags.equation = flags.equation + &"[" + &flags.comment + &"]";
-            } else if let &Arg::Roll(RollArg::Primitive(_)) = arg {
-                // Execute this roll before starting the next one
-                rolls.push(Roll::new(flags));
-
-                // Reset the flags
-                flags = RollFlags::new();
-            }
+    let requests = requests.into_inner();
+    if requests.len() > config.max_batch_size {
+        return Err(error_response(RollError::LimitExceeded {
+            offset: 0,
+            message: format!("Can't roll more than {} commands in a batch", config.max_batch_size),
+        }));
+    }
+
+    // Every entry is rolled on its own, an error in one doesn't stop the others
+    let results = requests.into_iter().map(|entry| {
+        match roll_request(entry.request, &characters, &config, &dice, Some(&*rolls)) {
+            Ok(result) => BatchResult { key: entry.key, error: None, result: Some(result) },
+            Err(error) => BatchResult { key: entry.key, error: Some(error), result: None },
         }
+    }).collect();
+
+    let elapsed = start.elapsed();
+    let response = BatchResponse {
+        rolls: results,
+        execution_time: (elapsed.as_secs() * 1000) + (elapsed.subsec_nanos() / 1000000) as u64,
+    };
+    Ok(Json(response))
+}
+
+#[derive(Deserialize)]
+pub struct CommitmentRollRequest {
+    /// Command to roll, any characters are allowed (e.g. "1d20+5 [Attack]")
+    pub command: String,
+
+    /// Seed chosen by the client, combined with the server seed to roll the dice
+    pub client_seed: String,
+
+    /// How divisions are rounded: floor (default), ceil, round or truncate
+    pub rounding: Option<String>,
+}
+
+#[post("/commitments", format = "application/json")]
+pub fn create_commitment(commitments: State<Commitments>) -> Result<Json<CommitmentResponse>, ErrorResponse> {
+    let commitment = Commitment::new().map_err(|e| error_response(RollError::Random { offset: 0, message: e.to_string() }))?;
+    let response = CommitmentResponse::new(&commitment);
+    commitments.lock().unwrap().insert(commitment.id.clone(), commitment);
+    Ok(Json(response))
+}
+
+// Rolling takes up a nonce, so it is a POST
+#[post("/commitments/<id>/rolls", format = "application/json", data = "<request>")]
+pub fn roll_with_commitment(id: String, request: Json<CommitmentRollRequest>, commitments: State<Commitments>, config: State<Config>, rolls: State<Rolls>) -> Result<Option<Json<RollsResponse>>, ErrorResponse> {
+    // Start the timer
+    let start = Instant::now();
+    let request = request.into_inner();
+    let command = request.command;
+
+    // Check the command before it uses up a nonce
+    let rounding = rounding(&request.rounding).map_err(error_response)?;
+    command::parse(&command).map_err(error_response)?;
 
-        // Build the final roll
-        let roll = Roll::new(flags);
-        // let original_equation = roll.equation.clone();
-
-        // Take all the dice from previous rolls and append them to this roll
-        // for mut r in rolls.into_iter() {
-            // roll.dice.append(&mut r.dice);
-            // roll.modifiers.append(&mut r.modifiers);
-            // roll.value += r.value;
-            // roll.raw_value += r.raw_value;
-            // roll.equation = r.equation + " + ";
-        // }
-        // roll.equation = roll.equation + &original_equation;
-
-        let elapsed = start.elapsed();
-        let response = RollsResponse {
-            roll,
-            execution_time: (elapsed.as_secs() * 1000) + (elapsed.subsec_nanos() / 1000000) as u64,
+    // Take the next nonce, commitments that have been revealed can no longer roll
+    let (seed, proof) = {
+        let mut commitments = commitments.lock().unwrap();
+        let commitment = match commitments.get_mut(&id) {
+            Some(commitment) => commitment,
+            None => return Ok(None),
         };
-        Some(Json(response))
-    } else {
-        None
+        let (nonce, seed) = match commitment.next_seed(&request.client_seed) {
+            Some(next) => next,
+            None => return Ok(None),
+        };
+        let proof = Proof {
+            commitment_id: commitment.id.clone(),
+            commitment: commitment.commitment.clone(),
+            client_seed: request.client_seed.clone(),
+            nonce,
+            rounding,
+        };
+        (seed, proof)
+    };
+    let mut roll = roll_api::roll_with(&command, rounding, &mut SeededRng::new(seed)).map_err(error_response)?;
+    record(&mut roll, &config, &rolls).map_err(error_response)?;
+
+    let elapsed = start.elapsed();
+    let response = RollsResponse {
+        roll,
+        seed: Some(seed),
+        proof: Some(proof),
+        substitutions: None,
+        execution_time: (elapsed.as_secs() * 1000) + (elapsed.subsec_nanos() / 1000000) as u64,
+    };
+    Ok(Some(Json(response)))
+}
+
+#[post("/commitments/<id>/reveal", format = "application/json")]
+pub fn reveal_commitment(id: String, commitments: State<Commitments>) -> Option<Json<CommitmentResponse>> {
+    let mut commitments = commitments.lock().unwrap();
+    let commitment = commitments.get_mut(&id)?;
+    commitment.reveal();
+    Some(Json(CommitmentResponse::new(commitment)))
+}
+
+#[derive(Default, FromForm)]
+pub struct DistributionOptions {
+    /// How divisions are rounded: floor (default), ceil, round or truncate
+    pub rounding: Option<String>,
+}
+
+#[get("/distribution/<command>", format = "application/json", rank = 2)]
+pub fn distribution(command: String) -> Result<Json<DistributionResponse>, ErrorResponse> {
+    distribution_with_options(command, DistributionOptions::default())
+}
+
+#[get("/distribution/<command>?<options>", format = "application/json")]
+pub fn distribution_with_options(command: String, options: DistributionOptions) -> Result<Json<DistributionResponse>, ErrorResponse> {
+    // Start the timer
+    let start = Instant::now();
+
+    let rounding = rounding(&options.rounding).map_err(error_response)?;
+
+    // Work out every possible result without rolling any dice
+    let command = command::parse(&command).map_err(error_response)?;
+    let distribution = distribution::distribution(&command, rounding).map_err(error_response)?;
+
+    let elapsed = start.elapsed();
+    let response = DistributionResponse {
+        distribution,
+        execution_time: (elapsed.as_secs() * 1000) + (elapsed.subsec_nanos() / 1000000) as u64,
+    };
+    Ok(Json(response))
+}
+
+#[derive(Default, FromForm)]
+pub struct SimulationOptions {
+    /// How divisions are rounded: floor (default), ceil, round or truncate
+    pub rounding: Option<String>,
+
+    /// Seed for reproducible simulations
+    pub seed: Option<u64>,
+
+    /// Number of times to roll the command, capped by `max_simulation_trials`
+    pub trials: Option<u32>,
+}
+
+#[get("/simulate/<command>", format = "application/json", rank = 2)]
+pub fn simulate(command: String, config: State<Config>) -> Result<Json<SimulationResponse>, ErrorResponse> {
+    simulate_with_options(command, SimulationOptions::default(), config)
+}
+
+#[get("/simulate/<command>?<options>", format = "application/json")]
+pub fn simulate_with_options(command: String, options: SimulationOptions, config: State<Config>) -> Result<Json<SimulationResponse>, ErrorResponse> {
+    // Start the timer
+    let start = Instant::now();
+
+    let rounding = rounding(&options.rounding).map_err(error_response)?;
+    let trials = options.trials.unwrap_or(10000).min(config.max_simulation_trials);
+    let time_limit = Duration::from_millis(config.max_simulation_time);
+
+    // Simulations need speed rather than secrecy, so every trial comes from one seeded generator
+    let seed = options.seed.unwrap_or_else(rand::random);
+    let simulation = simulation::simulate(&command, rounding, &mut SeededRng::new(seed), trials, time_limit).map_err(error_response)?;
+
+    let elapsed = start.elapsed();
+    let response = SimulationResponse {
+        simulation,
+        execution_time: (elapsed.as_secs() * 1000) + (elapsed.subsec_nanos() / 1000000) as u64,
+        seed,
+    };
+    Ok(Json(response))
+}
+
+#[get("/rolls/<id>", format = "application/json")]
+pub fn get_roll(id: String, rolls: State<Rolls>) -> Result<Option<Json<Roll>>, ErrorResponse> {
+    let record = rolls.get(&id).map_err(|e| error_response(RollError::Storage { offset: 0, message: e.to_string() }))?;
+
+    // Sealed rolls can't be looked up until they are revealed, nor secret rolls outside their room
+    Ok(record.and_then(|record| if record.is_visible_to(None, Utc::now()) { Some(Json(record.roll)) } else { None }))
+}
+
+#[derive(Default, FromForm)]
+pub struct HistoryOptions {
+    /// Only rolls made at or after this RFC 3339 timestamp
+    pub after: Option<String>,
+
+    /// Only rolls made before this RFC 3339 timestamp
+    pub before: Option<String>,
+
+    /// Only rolls with this label
+    pub label: Option<String>,
+
+    /// Page to return, starting from 1
+    pub page: Option<usize>,
+
+    /// Rolls per page, 20 by default and at most 100
+    pub per_page: Option<usize>,
+}
+
+#[derive(Serialize)]
+pub struct HistoryResponse {
+    pub page: usize,
+    pub per_page: usize,
+
+    /// Newest first
+    pub rolls: Vec<Roll>,
+
+    /// Number of rolls matching the filters across every page
+    pub total: usize,
+}
+
+/// Read an RFC 3339 timestamp option
+fn timestamp(value: &Option<String>) -> Result<Option<DateTime<Utc>>, RollError> {
+    match value {
+        &Some(ref value) => match DateTime::parse_from_rfc3339(value) {
+            Ok(timestamp) => Ok(Some(timestamp.with_timezone(&Utc))),
+            Err(_) => Err(RollError::Parse {
+                offset: 0,
+                message: format!("\"{}\" is not an RFC 3339 timestamp", value),
+            }),
+        },
+        &None => Ok(None),
+    }
+}
+
+/// List a page of the history, the rolls of a room when there is a viewer
+fn history(options: HistoryOptions, viewer: Option<Viewer>, rolls: &Rolls) -> Result<HistoryResponse, ErrorResponse> {
+    let page = options.page.unwrap_or(1).max(1);
+    let per_page = options.per_page.unwrap_or(20).max(1).min(100);
+    let query = RollQuery {
+        after: timestamp(&options.after).map_err(error_response)?,
+        before: timestamp(&options.before).map_err(error_response)?,
+        label: options.label,
+        limit: per_page,
+        offset: (page - 1) * per_page,
+        viewer,
+    };
+
+    let found = rolls.list(&query).map_err(|e| error_response(RollError::Storage { offset: 0, message: e.to_string() }))?;
+    Ok(HistoryResponse {
+        page,
+        per_page,
+        rolls: found.rolls,
+        total: found.total,
+    })
+}
+
+#[get("/rolls", format = "application/json", rank = 2)]
+pub fn list_rolls(rolls: State<Rolls>) -> Result<Json<HistoryResponse>, ErrorResponse> {
+    list_rolls_with_options(HistoryOptions::default(), rolls)
+}
+
+#[get("/rolls?<options>", format = "application/json", rank = 1)]
+pub fn list_rolls_with_options(options: HistoryOptions, rolls: State<Rolls>) -> Result<Json<HistoryResponse>, ErrorResponse> {
+    history(options, None, &rolls).map(Json)
+}
+
+#[derive(Serialize)]
+pub struct VerifyResponse {
+    /// If the roll was signed by this server and hasn't been changed since
+    pub is_valid: bool,
+
+    /// The parts of the roll that were checked
+    pub receipt: Receipt,
+}
+
+#[post("/verify", format = "application/json", data = "<roll>")]
+pub fn verify(roll: Json<Roll>, config: State<Config>) -> Json<VerifyResponse> {
+    let roll = roll.into_inner();
+    Json(VerifyResponse {
+        is_valid: receipt::verify(&roll, config.signing_key.as_bytes()),
+        receipt: Receipt::new(&roll),
+    })
+}
+
+#[derive(Serialize)]
+pub struct SealedResponse {
+    pub id: String,
+    pub commitment: String,
+    pub is_revealed: bool,
+    pub reveal_at: Option<DateTime<Utc>>,
+
+    /// Secret needed to reveal the roll early, only included when the roll is sealed
+    pub key: Option<String>,
+
+    /// Only included once the roll is revealed
+    pub roll: Option<Roll>,
+
+    /// Only included once the roll is revealed, needed to check the roll against the commitment
+    pub salt: Option<String>,
+}
+
+impl SealedResponse {
+    fn new(roll: &Roll, seal: &Seal) -> SealedResponse {
+        let is_revealed = !seal.is_hidden(Utc::now());
+        SealedResponse {
+            id: roll.id.clone(),
+            commitment: seal.commitment.clone(),
+            is_revealed,
+            reveal_at: seal.reveal_at,
+            key: None,
+            roll: if is_revealed { Some(roll.clone()) } else { None },
+            salt: if is_revealed { Some(seal.salt.clone()) } else { None },
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct SealedRequest {
+    /// When to reveal the roll on its own (RFC 3339), otherwise it stays sealed until revealed
+    pub reveal_at: Option<String>,
+
+    #[serde(flatten)]
+    pub request: RollRequest,
+}
+
+#[post("/sealed", format = "application/json", data = "<request>")]
+pub fn create_sealed(request: Json<SealedRequest>, characters: State<Characters>, config: State<Config>, dice: State<Dice>, rolls: State<Rolls>) -> Result<Json<SealedResponse>, ErrorResponse> {
+    let request = request.into_inner();
+    let reveal_at = timestamp(&request.reveal_at).map_err(error_response)?;
+
+    // The roll is recorded now, but left out of the history until it is revealed
+    let response = roll_request(request.request, &characters, &config, &dice, None).map_err(error_response)?;
+    let seal = Seal::new(&response.roll, reveal_at)
+        .map_err(|e| error_response(RollError::Random { offset: 0, message: e.to_string() }))?;
+    let mut record = Record::new(response.roll);
+    record.seal = Some(seal);
+    rolls.save(&record).map_err(|e| error_response(RollError::Storage { offset: 0, message: e.to_string() }))?;
+
+    let seal = record.seal.as_ref().unwrap();
+    let mut response = SealedResponse::new(&record.roll, seal);
+    response.key = Some(seal.key.clone());
+    Ok(Json(response))
+}
+
+/// Find a sealed roll in the history
+fn find_sealed(id: &str, rolls: &Rolls) -> Result<Option<Record>, ErrorResponse> {
+    let record = rolls.get(id).map_err(|e| error_response(RollError::Storage { offset: 0, message: e.to_string() }))?;
+    Ok(record.and_then(|record| if record.seal.is_some() { Some(record) } else { None }))
+}
+
+#[get("/sealed/<id>", format = "application/json")]
+pub fn get_sealed(id: String, rolls: State<Rolls>) -> Result<Option<Json<SealedResponse>>, ErrorResponse> {
+    // Rolls past their deadline are revealed without being saved again
+    let record = find_sealed(&id, &rolls)?;
+    Ok(record.map(|record| Json(SealedResponse::new(&record.roll, record.seal.as_ref().unwrap()))))
+}
+
+#[derive(Deserialize)]
+pub struct RevealRequest {
+    /// Secret returned when the roll was sealed
+    pub key: String,
+}
+
+#[post("/sealed/<id>/reveal", format = "application/json", data = "<request>")]
+pub fn reveal_sealed_roll(id: String, request: Json<RevealRequest>, rolls: State<Rolls>) -> Result<Option<Json<SealedResponse>>, ErrorResponse> {
+    let mut record = match find_sealed(&id, &rolls)? {
+        Some(record) => record,
+        None => return Ok(None),
+    };
+
+    // Only whoever sealed the roll can reveal it early
+    let revealed = {
+        let seal = record.seal.as_mut().unwrap();
+        if !seal.is_key(&request.key) {
+            return Err(forbidden("Wrong key for the sealed roll."));
+        }
+        seal.reveal()
+    };
+    if revealed {
+        rolls.save(&record).map_err(|e| error_response(RollError::Storage { offset: 0, message: e.to_string() }))?;
+    }
+    Ok(Some(Json(SealedResponse::new(&record.roll, record.seal.as_ref().unwrap()))))
+}
+
+/// A member as anyone in the room sees them, without their token
+#[derive(Serialize)]
+pub struct MemberResponse {
+    pub is_gm: bool,
+    pub name: String,
+}
+
+#[derive(Serialize)]
+pub struct RoomResponse {
+    pub id: String,
+    pub members: Vec<MemberResponse>,
+    pub name: String,
+    pub timestamp: DateTime<Utc>,
+}
+
+impl RoomResponse {
+    fn new(room: &Room) -> RoomResponse {
+        RoomResponse {
+            id: room.id.clone(),
+            members: room.members.iter().map(|member| MemberResponse {
+                is_gm: member.is_gm,
+                name: member.name.clone(),
+            }).collect(),
+            name: room.name.clone(),
+            timestamp: room.timestamp,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct JoinResponse {
+    pub room: RoomResponse,
+
+    /// The new member, with the token only they are given to roll in and listen to the room
+    pub member: Member,
+}
+
+#[derive(Deserialize)]
+pub struct RoomRequest {
+    pub name: String,
+
+    /// Name of the game master creating the room
+    pub gm_name: Option<String>,
+}
+
+#[post("/rooms", format = "application/json", data = "<request>")]
+pub fn create_room(request: Json<RoomRequest>, rooms: State<Rooms>) -> Result<Json<JoinResponse>, ErrorResponse> {
+    let request = request.into_inner();
+    let mut room = Room::new(&request.name);
+    let gm_name = request.gm_name.unwrap_or_else(|| "Game Master".to_string());
+    let member = room.join(&gm_name, true).map_err(error_response)?;
+    let response = JoinResponse { room: RoomResponse::new(&room), member };
+    rooms.lock().unwrap().insert(room.id.clone(), room);
+    Ok(Json(response))
+}
+
+#[get("/rooms/<id>", format = "application/json")]
+pub fn get_room(id: String, rooms: State<Rooms>) -> Option<Json<RoomResponse>> {
+    rooms.lock().unwrap().get(&id).map(|room| Json(RoomResponse::new(room)))
+}
+
+#[derive(Deserialize)]
+pub struct MemberRequest {
+    pub name: String,
+}
+
+#[post("/rooms/<id>/members", format = "application/json", data = "<request>")]
+pub fn join_room(id: String, request: Json<MemberRequest>, rooms: State<Rooms>) -> Result<Option<Json<JoinResponse>>, ErrorResponse> {
+    let mut rooms = rooms.lock().unwrap();
+    let room = match rooms.get_mut(&id) {
+        Some(room) => room,
+        None => return Ok(None),
+    };
+    let member = room.join(&request.name, false).map_err(error_response)?;
+    Ok(Some(Json(JoinResponse { room: RoomResponse::new(room), member })))
+}
+
+#[derive(Deserialize)]
+pub struct RoomRollRequest {
+    /// Token of the member making the roll
+    pub token: String,
+
+    /// Who can see the roll: public (default), gm or whisper
+    #[serde(default)]
+    pub visibility: Visibility,
+
+    /// Names of the members to whisper the roll to
+    #[serde(default)]
+    pub recipients: Vec<String>,
+
+    #[serde(flatten)]
+    pub request: RollRequest,
+}
+
+#[post("/rooms/<id>/rolls", format = "application/json", data = "<request>")]
+pub fn roll_in_room(id: String, request: Json<RoomRollRequest>, characters: State<Characters>, config: State<Config>, dice: State<Dice>, rolls: State<Rolls>, rooms: State<Rooms>) -> Result<Option<Json<RollsResponse>>, ErrorResponse> {
+    let request = request.into_inner();
+
+    // Only hold the lock to check the member and the recipients, so rolling and saving don't hold
+    // up every other room
+    let (member, room_id) = {
+        let rooms = rooms.lock().unwrap();
+        let room = match rooms.get(&id) {
+            Some(room) => room,
+            None => return Ok(None),
+        };
+        let member = match room.authenticate(&request.token) {
+            Some(member) => member.name.clone(),
+            None => return Err(forbidden("Unknown member token.")),
+        };
+        if let Some(recipient) = request.recipients.iter().find(|recipient| room.member(recipient).is_none()) {
+            return Err(error_response(RollError::UnsupportedArgument {
+                offset: 0,
+                message: format!("Can't whisper to \"{}\", they aren't in the room", recipient),
+            }));
+        }
+        (member, room.id.clone())
+    };
+
+    // Every roll is recorded, but only the members who can see it find it in the history
+    let response = roll_request(request.request, &characters, &config, &dice, None).map_err(error_response)?;
+    let audience = Audience {
+        member,
+        recipients: request.recipients,
+        room_id,
+        visibility: request.visibility,
+    };
+    let mut record = Record::new(response.roll.clone());
+    record.audience = Some(audience.clone());
+    rolls.save(&record).map_err(|e| error_response(RollError::Storage { offset: 0, message: e.to_string() }))?;
+
+    // The room may have closed while rolling, the roll is kept in the history either way
+    if let Some(room) = rooms.lock().unwrap().get_mut(&id) {
+        room.broadcast(&RoomEvent { audience, roll: response.roll.clone() });
+    }
+    Ok(Some(Json(response)))
+}
+
+/// Secret a member sends in the `X-Member-Token` header
+pub struct MemberToken(pub String);
+
+impl<'a, 'r> FromRequest<'a, 'r> for MemberToken {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<MemberToken, ()> {
+        match request.headers().get_one("X-Member-Token") {
+            Some(token) => Outcome::Success(MemberToken(token.to_string())),
+            None => Outcome::Forward(()),
+        }
+    }
+}
+
+#[get("/rooms/<id>/rolls", format = "application/json", rank = 2)]
+pub fn list_room_rolls(id: String, token: Option<MemberToken>, rolls: State<Rolls>, rooms: State<Rooms>) -> Result<Option<Json<HistoryResponse>>, ErrorResponse> {
+    list_room_rolls_with_options(id, HistoryOptions::default(), token, rolls, rooms)
+}
+
+#[get("/rooms/<id>/rolls?<options>", format = "application/json", rank = 1)]
+pub fn list_room_rolls_with_options(id: String, options: HistoryOptions, token: Option<MemberToken>, rolls: State<Rolls>, rooms: State<Rooms>) -> Result<Option<Json<HistoryResponse>>, ErrorResponse> {
+    let viewer = {
+        let rooms = rooms.lock().unwrap();
+        let room = match rooms.get(&id) {
+            Some(room) => room,
+            None => return Ok(None),
+        };
+        let member = match token {
+            Some(MemberToken(ref token)) => room.authenticate(token),
+            None => return Err(forbidden("Send the member's token in the X-Member-Token header.")),
+        };
+        match member {
+            Some(member) => Viewer { member: member.clone(), room_id: room.id.clone() },
+            None => return Err(forbidden("Unknown member token.")),
+        }
+    };
+    history(options, Some(viewer), &rolls).map(|response| Some(Json(response)))
+}
+
+#[derive(Deserialize)]
+pub struct MacroRequest {
+    pub name: String,
+
+    /// Command with `{variable}` placeholders (e.g. "1d20+{str}+{prof}")
+    pub command: String,
+}
+
+#[derive(Deserialize)]
+pub struct MacroUpdate {
+    pub command: String,
+}
+
+#[derive(Serialize)]
+pub struct MacroList {
+    pub macros: Vec<Macro>,
+}
+
+/// A new macro, with the token only its creator is given to change or delete it
+#[derive(Serialize)]
+pub struct MacroCreated {
+    #[serde(flatten)]
+    pub saved: Macro,
+
+    pub owner_token: String,
+}
+
+/// Secret sent in the `X-Owner-Token` header to change or delete a macro or character
+pub struct OwnerToken(pub String);
+
+impl<'a, 'r> FromRequest<'a, 'r> for OwnerToken {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<OwnerToken, ()> {
+        match request.headers().get_one("X-Owner-Token") {
+            Some(token) => Outcome::Success(OwnerToken(token.to_string())),
+            None => Outcome::Forward(()),
+        }
+    }
+}
+
+/// Refuse a change unless the owner's token was sent
+fn check_owner<F: Fn(&str) -> bool>(token: Option<OwnerToken>, is_owner: F) -> Result<(), ErrorResponse> {
+    match token {
+        Some(OwnerToken(ref token)) if is_owner(token) => Ok(()),
+        Some(_) => Err(forbidden("Wrong owner token.")),
+        None => Err(forbidden("Send the token given when it was created in the X-Owner-Token header.")),
+    }
+}
+
+#[get("/macros", format = "application/json")]
+pub fn list_macros(macros: State<Macros>) -> Json<MacroList> {
+    let mut list: Vec<Macro> = macros.lock().unwrap().values().cloned().collect();
+    list.sort_by(|a, b| a.name.cmp(&b.name));
+    Json(MacroList { macros: list })
+}
+
+#[post("/macros", format = "application/json", data = "<request>")]
+pub fn create_macro(request: Json<MacroRequest>, macros: State<Macros>) -> Result<Json<MacroCreated>, ErrorResponse> {
+    let request = request.into_inner();
+    let saved = Macro::new(&request.name, &request.command).map_err(error_response)?;
+    let mut macros = macros.lock().unwrap();
+    if macros.contains_key(&saved.name) {
+        return Err(error_response(RollError::InvalidRange {
+            offset: 0,
+            message: format!("A macro named \"{}\" already exists", saved.name),
+        }));
+    }
+    macros.insert(saved.name.clone(), saved.clone());
+    let owner_token = saved.owner_token.clone();
+    Ok(Json(MacroCreated { saved, owner_token }))
+}
+
+#[get("/macros/<name>", format = "application/json")]
+pub fn get_macro(name: String, macros: State<Macros>) -> Option<Json<Macro>> {
+    macros.lock().unwrap().get(&name).cloned().map(Json)
+}
+
+#[put("/macros/<name>", format = "application/json", data = "<request>")]
+pub fn update_macro(name: String, request: Json<MacroUpdate>, token: Option<OwnerToken>, macros: State<Macros>) -> Result<Option<Json<Macro>>, ErrorResponse> {
+    let mut saved = Macro::new(&name, &request.command).map_err(error_response)?;
+    let mut macros = macros.lock().unwrap();
+    match macros.get(&name) {
+        Some(existing) => {
+            check_owner(token, |token| existing.is_owner(token))?;
+            saved.owner_token = existing.owner_token.clone();
+        },
+        None => return Ok(None),
+    }
+    macros.insert(name, saved.clone());
+    Ok(Some(Json(saved)))
+}
+
+#[delete("/macros/<name>", format = "application/json")]
+pub fn delete_macro(name: String, token: Option<OwnerToken>, macros: State<Macros>) -> Result<Option<Json<Macro>>, ErrorResponse> {
+    let mut macros = macros.lock().unwrap();
+    match macros.get(&name) {
+        Some(existing) => check_owner(token, |token| existing.is_owner(token))?,
+        None => return Ok(None),
+    }
+    Ok(macros.remove(&name).map(Json))
+}
+
+#[derive(Deserialize)]
+pub struct MacroRollRequest {
+    /// Value of each placeholder, a number or a command (e.g. { "str": 3, "weapon": "1d8" })
+    #[serde(default)]
+    pub variables: HashMap<String, Value>,
+
+    /// Character whose attributes are used in the macro (e.g. "1d20+@str_mod+{bonus}")
+    pub character: Option<String>,
+
+    /// Comment for the roll, added after any comments in the command
+    pub comment: Option<String>,
+
+    /// Name for the roll, the name of the macro by default
+    pub label: Option<String>,
+
+    /// How divisions are rounded: floor (default), ceil, round or truncate
+    pub rounding: Option<String>,
+
+    /// Seed for reproducible rolls, otherwise the operating system's random numbers are used
+    pub seed: Option<u64>,
+}
+
+#[derive(Serialize)]
+pub struct MacroRollResponse {
+    /// The command after every placeholder was substituted
+    pub equation: String,
+
+    #[serde(flatten)]
+    pub result: RollsResponse,
+}
+
+#[post("/macros/<name>/roll", format = "application/json", data = "<request>")]
+pub fn roll_macro(name: String, request: Json<MacroRollRequest>, characters: State<Characters>, config: State<Config>, dice: State<Dice>, macros: State<Macros>, rolls: State<Rolls>) -> Result<Option<Json<MacroRollResponse>>, ErrorResponse> {
+    let saved = match macros.lock().unwrap().get(&name) {
+        Some(saved) => saved.clone(),
+        None => return Ok(None),
+    };
+
+    let request = request.into_inner();
+    let equation = saved.resolve(&request.variables).map_err(error_response)?;
+    let result = roll_request(RollRequest {
+        command: equation.clone(),
+        character: request.character,
+        comment: request.comment,
+        label: request.label.or(Some(saved.name)),
+        rounding: request.rounding,
+        seed: request.seed,
+    }, &characters, &config, &dice, Some(&*rolls)).map_err(error_response)?;
+    Ok(Some(Json(MacroRollResponse { equation, result })))
+}
+
+#[derive(Deserialize)]
+pub struct CharacterRequest {
+    pub name: String,
+
+    /// Value of each attribute, by name (e.g. { "str_mod": 3, "prof": 2 })
+    #[serde(default)]
+    pub attributes: BTreeMap<String, i32>,
+}
+
+/// A new character, with the token only its creator is given to change or delete it
+#[derive(Serialize)]
+pub struct CharacterCreated {
+    #[serde(flatten)]
+    pub character: Character,
+
+    pub owner_token: String,
+}
+
+#[post("/characters", format = "application/json", data = "<request>")]
+pub fn create_character(request: Json<CharacterRequest>, characters: State<Characters>) -> Result<Json<CharacterCreated>, ErrorResponse> {
+    let request = request.into_inner();
+    let character = Character::new(&request.name, request.attributes).map_err(error_response)?;
+    characters.lock().unwrap().insert(character.id.clone(), character.clone());
+    let owner_token = character.owner_token.clone();
+    Ok(Json(CharacterCreated { character, owner_token }))
+}
+
+#[get("/characters/<id>", format = "application/json")]
+pub fn get_character(id: String, characters: State<Characters>) -> Option<Json<Character>> {
+    characters.lock().unwrap().get(&id).cloned().map(Json)
+}
+
+#[put("/characters/<id>", format = "application/json", data = "<request>")]
+pub fn update_character(id: String, request: Json<CharacterRequest>, token: Option<OwnerToken>, characters: State<Characters>) -> Result<Option<Json<Character>>, ErrorResponse> {
+    let request = request.into_inner();
+    let mut character = Character::new(&request.name, request.attributes).map_err(error_response)?;
+    let mut characters = characters.lock().unwrap();
+    match characters.get(&id) {
+        Some(existing) => {
+            check_owner(token, |token| existing.is_owner(token))?;
+            character.owner_token = existing.owner_token.clone();
+        },
+        None => return Ok(None),
+    }
+    character.id = id.clone();
+    characters.insert(id, character.clone());
+    Ok(Some(Json(character)))
+}
+
+#[delete("/characters/<id>", format = "application/json")]
+pub fn delete_character(id: String, token: Option<OwnerToken>, characters: State<Characters>) -> Result<Option<Json<Character>>, ErrorResponse> {
+    let mut characters = characters.lock().unwrap();
+    match characters.get(&id) {
+        Some(existing) => check_owner(token, |token| existing.is_owner(token))?,
+        None => return Ok(None),
+    }
+    Ok(characters.remove(&id).map(Json))
+}
+
+#[derive(Serialize)]
+pub struct DiceList {
+    pub dice: Vec<SymbolicDie>,
+}
+
+#[get("/dice", format = "application/json")]
+pub fn list_dice(dice: State<Dice>) -> Json<DiceList> {
+    Json(DiceList { dice: dice.lock().unwrap().dice().into_iter().cloned().collect() })
+}
+
+#[post("/dice", format = "application/json", data = "<die>")]
+pub fn create_die(die: Json<SymbolicDie>, dice: State<Dice>) -> Result<Json<SymbolicDie>, ErrorResponse> {
+    let die = die.into_inner();
+    let mut dice = dice.lock().unwrap();
+    if dice.is_builtin(&die.name) {
+        return Err(forbidden("Built-in dice can't be changed."));
+    }
+    if dice.get(&die.name).is_some() {
+        return Err(error_response(RollError::InvalidRange {
+            offset: 0,
+            message: format!("A die named \"{}\" already exists", die.name),
+        }));
+    }
+    dice.register(die.clone()).map_err(error_response)?;
+    Ok(Json(die))
+}
+
+#[get("/dice/<name>", format = "application/json")]
+pub fn get_die(name: String, dice: State<Dice>) -> Option<Json<SymbolicDie>> {
+    dice.lock().unwrap().get(&name).cloned().map(Json)
+}
+
+#[derive(Deserialize)]
+pub struct DieUpdate {
+    /// Pairs of symbols that cancel each other out when the die is rolled (e.g. [["hit", "miss"]])
+    #[serde(default)]
+    pub cancellations: Vec<(String, String)>,
+
+    /// Symbols on each face, by name (e.g. [{}, { "success": 1, "advantage": 1 }])
+    pub faces: Vec<symbolic::Face>,
+}
+
+#[put("/dice/<name>", format = "application/json", data = "<request>")]
+pub fn update_die(name: String, request: Json<DieUpdate>, dice: State<Dice>) -> Result<Option<Json<SymbolicDie>>, ErrorResponse> {
+    let request = request.into_inner();
+    let die = SymbolicDie { name: name.clone(), cancellations: request.cancellations, faces: request.faces };
+    let mut dice = dice.lock().unwrap();
+    if dice.is_builtin(&name) {
+        return Err(forbidden("Built-in dice can't be changed."));
+    }
+    if dice.get(&name).is_none() {
+        return Ok(None);
+    }
+    dice.register(die.clone()).map_err(error_response)?;
+    Ok(Some(Json(die)))
+}
+
+#[delete("/dice/<name>", format = "application/json")]
+pub fn delete_die(name: String, dice: State<Dice>) -> Result<Option<Json<SymbolicDie>>, ErrorResponse> {
+    let mut dice = dice.lock().unwrap();
+    if dice.is_builtin(&name) {
+        return Err(forbidden("Built-in dice can't be changed."));
     }
+    dice.remove(&name).map(|die| die.map(Json)).map_err(error_response)
 }